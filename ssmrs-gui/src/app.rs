@@ -2,19 +2,46 @@ use std::sync::Arc;
 
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 pub struct SSMRS {
-    file_content: Arc<Mutex<Option<String>>>,
+    /// Name and contents of the loaded file.
+    file_content: Picked<String>,
+    snapshot_content: Picked<String>,
+    object_content: Picked<Vec<u8>>,
+    /// Rendered errors and warnings of the loaded file.
+    diagnostics: Vec<(Severity, String)>,
+    /// Loaded snapshot, which takes the place of the file on a reset.
+    snapshot: Option<Snapshot>,
+    /// Loaded object, which takes the place of the file on a reset.
+    object: Option<Object>,
     code: Code,
+    source_map: SourceMap,
     cpu: Option<Cpu>,
     halted: bool,
     running: bool,
+    fault: Option<CpuFault>,
+    stopped: Option<StopReason>,
+    breakpoints: BTreeSet<i32>,
+    waiting_for_input: bool,
+    error: Option<String>,
+    config: CpuConfig,
+    show_settings: bool,
+    /// Instructions after which Run pauses, or 0 for no limit.
+    max_steps: u64,
+    /// The instruction count when Run was last clicked.
+    run_start: u64,
+    input: Arc<Mutex<VecDeque<char>>>,
+    input_text: String,
     max_sp: usize,
     initial_sp: usize,
     message_queue: Arc<RwLock<Vec<String>>>,
+    log_queue: Arc<RwLock<Vec<String>>>,
     verbosity: u8,
     annotations: HashMap<usize, Annote>,
     content: HashMap<usize, (Color32, String)>,
 }
 
+/// The name and contents of a file picked in a dialog, once it has been read.
+type Picked<T> = Arc<Mutex<Option<(String, T)>>>;
+
 struct Annote {
     text: String,
     color: Color,
@@ -23,15 +50,23 @@ struct Annote {
     end: i32,
 }
 
+use std::collections::{BTreeSet, VecDeque};
 use std::future::Future;
 
 use eframe::epaint::ahash::{HashMap, HashMapExt};
 use egui::mutex::{Mutex, RwLock};
 use egui::output::OpenUrl;
 use egui::{Color32, RichText, Ui};
-use ssmrs::instruction::Color;
+
+use crate::io::QueueIo;
+use ssmrs::assembler::Assembler;
+use ssmrs::diagnostic::Severity;
+use ssmrs::disasm::disassemble;
+use ssmrs::instruction::{Color, Encoding};
+use ssmrs::object::Object;
 use ssmrs::register::Reg;
-use ssmrs::{Code, Cpu, Instr, Parser};
+use ssmrs::source_map::SourceMap;
+use ssmrs::{Code, Cpu, CpuConfig, CpuFault, Instr, RunLimits, Snapshot, SourceInstr, StopReason};
 #[cfg(not(target_arch = "wasm32"))]
 fn execute<F: Future<Output = ()> + Send + 'static>(f: F) {
     tokio::spawn(f);
@@ -42,17 +77,109 @@ fn execute<F: Future<Output = ()> + 'static>(f: F) {
 }
 
 impl SSMRS {
+    /// Executes one instruction, stopping early at a breakpoint unless `single_step` is set.
+    fn step(&mut self, single_step: bool) {
+        let Some(cpu) = &mut self.cpu else {
+            return;
+        };
+        let reason = if single_step {
+            if cpu.step_forward() {
+                return;
+            }
+            let res = cpu.step();
+            StopReason::from_step(res, cpu.read_registers().pc)
+        } else {
+            cpu.run_until(RunLimits::steps(1))
+        };
+        self.waiting_for_input = reason == StopReason::WaitingForInput;
+        self.stopped = None;
+        match reason {
+            StopReason::StepLimit { .. } | StopReason::Watchpoint { .. } => {
+                let pc = cpu.read_registers().pc as usize;
+                if let Some(annote) = self.annotations.get(&pc) {
+                    let start = (annote.start + cpu.read_registers()[annote.reg]) as usize;
+                    let end = (annote.end + cpu.read_registers()[annote.reg]) as usize;
+                    for i in start..=end {
+                        self.content
+                            .insert(i, (convert_color(&annote.color), annote.text.clone()));
+                    }
+                }
+                if let StopReason::Watchpoint { .. } = reason {
+                    self.running = false;
+                    self.stopped = Some(reason);
+                } else {
+                    let steps = cpu.instruction_count().saturating_sub(self.run_start);
+                    if self.running && self.max_steps > 0 && steps >= self.max_steps {
+                        self.running = false;
+                        self.stopped = Some(StopReason::StepLimit {
+                            steps,
+                            pc: cpu.read_registers().pc,
+                        });
+                    }
+                }
+            }
+            StopReason::WaitingForInput
+            | StopReason::StartOfHistory
+            | StopReason::Timeout { .. } => {}
+            StopReason::Breakpoint(_) => {
+                self.running = false;
+                self.stopped = Some(reason);
+            }
+            StopReason::Halted => {
+                self.running = false;
+                self.halted = true;
+            }
+            StopReason::Fault(fault) => {
+                self.running = false;
+                self.halted = true;
+                self.fault = Some(fault);
+            }
+        }
+    }
+
+    /// Moves through the recorded history until `position` steps can be undone.
+    fn seek(&mut self, position: usize) {
+        let Some(cpu) = &mut self.cpu else {
+            return;
+        };
+        while cpu.history_len() > position && cpu.step_back() {}
+        while cpu.history_len() < position && cpu.step_forward() {}
+        self.running = false;
+        self.halted = false;
+        self.fault = None;
+        self.stopped = None;
+        self.waiting_for_input = false;
+    }
+
     /// Called once before the first frame.
     pub fn new(_cc: &eframe::CreationContext<'_>) -> Self {
         Self {
             file_content: Arc::new(Mutex::new(None)),
-            code: vec![Instr::HALT],
+            snapshot_content: Arc::new(Mutex::new(None)),
+            object_content: Arc::new(Mutex::new(None)),
+            snapshot: None,
+            object: None,
+            diagnostics: Vec::new(),
+            code: vec![Instr::HALT.into()],
+            source_map: SourceMap::default(),
             cpu: None,
             halted: true,
             running: false,
+            fault: None,
+            stopped: None,
+            breakpoints: BTreeSet::new(),
+            waiting_for_input: false,
+            error: None,
+            config: CpuConfig::default(),
+            show_settings: false,
+            max_steps: 0,
+            run_start: 0,
+            input: Arc::new(Mutex::new(VecDeque::new())),
+            input_text: String::new(),
             max_sp: 0,
             initial_sp: 0,
             message_queue: Arc::new(RwLock::new(Vec::new())),
+            log_queue: Arc::new(RwLock::new(Vec::new())),
             verbosity: 0,
             annotations: HashMap::new(),
             content: HashMap::new(),
@@ -60,23 +187,38 @@ impl SSMRS {
     }
 }
 
-fn open_file(z: Arc<Mutex<Option<String>>>) {
+fn open_file<T: Send + 'static>(
+    z: Picked<T>,
+    name: &str,
+    extensions: &[&str],
+    decode: fn(Vec<u8>) -> Option<T>,
+) {
     use rfd::AsyncFileDialog;
     let task = AsyncFileDialog::new()
-        .add_filter("Simple Stack Machine Assembly", &["ssm", "asm"])
+        .add_filter(name, extensions)
         .pick_file();
     execute(async move {
         let file = task.await;
         if let Some(file) = file {
             let contents = file.read().await;
-            if let Ok(contents) = String::from_utf8(contents) {
+            if let Some(contents) = decode(contents) {
                 let mut z = z.lock();
-                *z = Some(contents);
+                *z = Some((file.file_name(), contents));
             }
         }
     })
 }
 
+fn save_file(contents: String, file_name: &str) {
+    use rfd::AsyncFileDialog;
+    let task = AsyncFileDialog::new().set_file_name(file_name).save_file();
+    execute(async move {
+        if let Some(file) = task.await {
+            let _ = file.write(contents.as_bytes()).await;
+        }
+    })
+}
+
 fn open_url(ui: &Ui, url: &str, new_tab: bool) {
     ui.ctx().output_mut(|o| {
         o.open_url = Some(OpenUrl {
@@ -92,60 +234,186 @@ impl eframe::App for SSMRS {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         {
             let z = self.file_content.lock();
-            if let Some(code) = z.as_ref() {
-                let p = ssmrs::parse().parse(code.as_str());
-                if let Ok(mut p) = p {
-                    p.push(Instr::HALT);
+            if let Some((name, code)) = z.as_ref() {
+                let mut assembler = Assembler::new();
+                let assembly = assembler.assemble(&[(name, code)]);
+                let diagnostics = match &assembly {
+                    Ok(assembly) => &assembly.warnings,
+                    Err(diagnostics) => diagnostics,
+                };
+                self.diagnostics = diagnostics
+                    .iter()
+                    .map(|d| (d.severity, assembler.sources().render(d)))
+                    .collect();
+                if let Ok(mut assembly) = assembly {
+                    assembly.code.push(Instr::HALT.into());
                     if self.cpu.is_none() {
-                        self.halted = false;
-                        let q = self.message_queue.clone();
-                        self.cpu = Some(Cpu::new(
-                            self.verbosity,
-                            Box::new(move |s| {
-                                let mut q = q.write();
-                                q.push(s);
-                            }),
-                        ));
-                        if let Some(cpu) = &mut self.cpu {
-                            cpu.load_code(p.clone());
-                            self.initial_sp = cpu.read_registers().sp as usize;
-                            self.max_sp = self.initial_sp;
+                        self.fault = None;
+                        let io = QueueIo::new(
+                            self.message_queue.clone(),
+                            self.log_queue.clone(),
+                            self.input.clone(),
+                        );
+                        let cpu =
+                            Cpu::with_config(self.config.clone(), self.verbosity, Box::new(io))
+                                .map_err(|e| e.to_string())
+                                .and_then(|mut cpu| {
+                                    cpu.load_assembly(assembly.clone())
+                                        .map(|_| cpu)
+                                        .map_err(|e| e.to_string())
+                                });
+                        match cpu {
+                            Ok(mut cpu) => {
+                                for &addr in &self.breakpoints {
+                                    cpu.add_breakpoint(addr);
+                                }
+                                self.initial_sp = cpu.read_registers().sp as usize;
+                                self.max_sp = self.initial_sp;
+                                self.cpu = Some(cpu);
+                                self.halted = false;
+                                self.error = None;
+                            }
+                            Err(e) => self.error = Some(e),
                         }
                     }
-                    self.code = p;
+                    self.code = assembly.code;
+                    self.source_map = assembly.source_map;
                 }
             }
         }
 
-        {
-            if self.running {
-                if let Some(cpu) = &mut self.cpu {
-                    let res = cpu.step();
-                    if !res {
-                        self.running = false;
-                        self.halted = true;
-                    } else {
-                        let pc = cpu.read_registers().pc as usize;
-                        if let Some(annote) = self.annotations.get(&pc) {
-                            let start = (annote.start + cpu.read_registers()[annote.reg]) as usize;
-                            let end = (annote.end + cpu.read_registers()[annote.reg]) as usize;
-                            for i in start..=end {
-                                self.content
-                                    .insert(i, (convert_color(&annote.color), annote.text.clone()));
-                            }
-                        }
+        let loaded = self.snapshot_content.lock().take();
+        if let Some((_, json)) = loaded {
+            match Snapshot::from_json(&json) {
+                Ok(snapshot) => {
+                    *self.file_content.lock() = None;
+                    self.diagnostics.clear();
+                    self.code = disassemble(
+                        &snapshot.memory[..snapshot.code_size],
+                        &snapshot.labels,
+                        snapshot.config.encoding,
+                    );
+                    self.source_map = SourceMap::default();
+                    self.config = snapshot.config.clone();
+                    self.breakpoints = snapshot.breakpoints.clone();
+                    self.snapshot = Some(snapshot);
+                    self.object = None;
+                    self.cpu = None;
+                }
+                Err(e) => self.error = Some(e.to_string()),
+            }
+        }
+        let loaded = self.object_content.lock().take();
+        if let Some((_, bytes)) = loaded {
+            match Object::from_bytes(&bytes) {
+                Ok(object) => {
+                    *self.file_content.lock() = None;
+                    self.diagnostics.clear();
+                    self.code = disassemble(&object.words, &object.symbols, object.encoding);
+                    self.config.encoding = object.encoding;
+                    self.source_map = object.source_map.clone().unwrap_or_default();
+                    self.breakpoints.clear();
+                    self.snapshot = None;
+                    self.object = Some(object);
+                    self.cpu = None;
+                }
+                Err(e) => self.error = Some(e.to_string()),
+            }
+        }
+        if let (None, Some(object)) = (&self.cpu, &self.object) {
+            self.fault = None;
+            let io = QueueIo::new(
+                self.message_queue.clone(),
+                self.log_queue.clone(),
+                self.input.clone(),
+            );
+            let cpu = Cpu::with_config(self.config.clone(), self.verbosity, Box::new(io))
+                .map_err(|e| e.to_string())
+                .and_then(|mut cpu| {
+                    cpu.load_object(object.clone())
+                        .map(|_| cpu)
+                        .map_err(|e| e.to_string())
+                });
+            match cpu {
+                Ok(mut cpu) => {
+                    for &addr in &self.breakpoints {
+                        cpu.add_breakpoint(addr);
+                    }
+                    self.initial_sp = cpu.read_registers().sp as usize;
+                    self.max_sp = self.initial_sp;
+                    self.cpu = Some(cpu);
+                    self.halted = false;
+                    self.error = None;
+                }
+                Err(e) => self.error = Some(e),
+            }
+        }
+        if let (None, Some(snapshot)) = (&self.cpu, &self.snapshot) {
+            self.fault = None;
+            let io = QueueIo::new(
+                self.message_queue.clone(),
+                self.log_queue.clone(),
+                self.input.clone(),
+            );
+            match Cpu::from_snapshot(snapshot.clone(), self.verbosity, Box::new(io)) {
+                Ok(mut cpu) => {
+                    for &addr in snapshot.breakpoints.difference(&self.breakpoints) {
+                        cpu.remove_breakpoint(addr);
+                    }
+                    for &addr in &self.breakpoints {
+                        cpu.add_breakpoint(addr);
                     }
-                    ctx.request_repaint();
+                    self.initial_sp = snapshot.code_size;
+                    self.max_sp = (cpu.read_registers().sp.max(0) as usize).max(self.initial_sp);
+                    self.cpu = Some(cpu);
+                    self.halted = false;
+                    self.error = None;
                 }
+                Err(e) => self.error = Some(e.to_string()),
             }
         }
 
+        if self.running && self.cpu.is_some() {
+            self.step(false);
+            ctx.request_repaint();
+        }
+
         egui::TopBottomPanel::top("menu_bar").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
                 ui.menu_button("File", |ui| {
                     if ui.button("load file").clicked() {
-                        open_file(self.file_content.clone());
+                        open_file(
+                            self.file_content.clone(),
+                            "Simple Stack Machine Assembly",
+                            &["ssm", "asm"],
+                            |bytes| String::from_utf8(bytes).ok(),
+                        );
                         self.cpu = None;
+                        self.snapshot = None;
+                        self.object = None;
+                        self.breakpoints.clear();
+                        ui.close_menu();
+                    }
+
+                    if ui.button("load snapshot").clicked() {
+                        open_file(
+                            self.snapshot_content.clone(),
+                            "Snapshot",
+                            &["json"],
+                            |bytes| String::from_utf8(bytes).ok(),
+                        );
+                        ui.close_menu();
+                    }
+
+                    if ui.button("load object").clicked() {
+                        open_file(self.object_content.clone(), "Object", &["ssmo"], Some);
+                        ui.close_menu();
+                    }
+
+                    if ui.button("save snapshot").clicked() {
+                        if let Some(cpu) = &self.cpu {
+                            save_file(cpu.snapshot().to_json(), "snapshot.json");
+                        }
                         ui.close_menu();
                     }
 
@@ -154,6 +422,9 @@ impl eframe::App for SSMRS {
                         ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                     }
                 });
+                if ui.button("Settings").clicked() {
+                    self.show_settings = true;
+                }
                 ui.menu_button("Verbosity", |ui| {
                     if ui.radio_value(&mut self.verbosity, 0, "None").clicked()
                         || ui.radio_value(&mut self.verbosity, 1, "Low").clicked()
@@ -179,42 +450,68 @@ impl eframe::App for SSMRS {
         });
         egui::TopBottomPanel::top("action_bar").show(ctx, |ui| {
             ui.horizontal(|ui| {
-                if ui.button("Single Step").clicked() && !self.halted {
-                    if let Some(cpu) = &mut self.cpu {
-                        if !cpu.step() {
-                            self.halted = true;
-                        } else {
-                            let pc = cpu.read_registers().pc as usize;
-                            if let Some(annote) = self.annotations.get(&pc) {
-                                let start =
-                                    (annote.start + cpu.read_registers()[annote.reg]) as usize;
-                                let end = (annote.end + cpu.read_registers()[annote.reg]) as usize;
-                                for i in start..=end {
-                                    self.content.insert(
-                                        i,
-                                        (convert_color(&annote.color), annote.text.clone()),
-                                    );
-                                }
-                            }
-                        }
+                if ui.button("Step Back").clicked() {
+                    if let Some(cpu) = &self.cpu {
+                        self.seek(cpu.history_len().saturating_sub(1));
                     }
                 }
 
-                if ui.button("Run").clicked() && !self.halted && self.cpu.is_some() {
-                    self.running = true;
+                if ui.button("Single Step").clicked() && !self.halted {
+                    self.step(true);
+                }
+
+                if ui.button("Run").clicked() && !self.halted {
+                    if let Some(cpu) = &self.cpu {
+                        self.running = true;
+                        self.run_start = cpu.instruction_count();
+                    }
                 }
 
                 if ui.button("Pause execution").clicked() {
                     self.running = false;
                 }
 
+                if let Some(cpu) = &self.cpu {
+                    let mut position = cpu.history_len();
+                    let end = position + cpu.future_len();
+                    if ui
+                        .add(egui::Slider::new(&mut position, 0..=end).text("Timeline"))
+                        .changed()
+                    {
+                        self.seek(position);
+                    }
+                }
+
                 if ui.button("Reset").clicked() {
                     self.cpu = None;
                     self.halted = true;
+                    self.stopped = None;
+                    self.waiting_for_input = false;
+                    self.input.lock().clear();
+                }
+
+                if let Some(fault) = &self.fault {
+                    let text = match self.source_map.get(fault.pc) {
+                        Some(location) => format!("error: {} (line {})", fault, location.line),
+                        None => format!("error: {}", fault),
+                    };
+                    ui.label(RichText::new(text).color(Color32::RED));
+                } else if let Some(error) = &self.error {
+                    ui.label(RichText::new(format!("error: {}", error)).color(Color32::RED));
+                } else if let Some(reason) = &self.stopped {
+                    ui.label(RichText::new(format!("stopped: {}", reason)).color(Color32::YELLOW));
+                } else if self.waiting_for_input {
+                    ui.label(RichText::new("waiting for input").color(Color32::YELLOW));
                 }
             });
         });
 
+        egui::Window::new("Settings")
+            .open(&mut self.show_settings)
+            .show(ctx, |ui| {
+                settings_ui(ui, &mut self.config, &mut self.max_steps)
+            });
+
         egui::TopBottomPanel::bottom("bottom_bar").show(ctx, |ui| {
             // The bottom panel is often a good place for a status bar:
             ui.horizontal(|ui| {
@@ -232,14 +529,34 @@ impl eframe::App for SSMRS {
                         ui.horizontal(|ui| {
                             ui.heading("Trap output");
                             if ui.button("Clear output").clicked() {
-                                let mut q = self.message_queue.write();
-                                q.clear();
+                                self.message_queue.write().clear();
+                                self.log_queue.write().clear();
+                            }
+                            let edit = ui.add(
+                                egui::TextEdit::singleline(&mut self.input_text)
+                                    .hint_text("Program input"),
+                            );
+                            if ui.button("Send").clicked()
+                                || (edit.lost_focus()
+                                    && ui.input(|i| i.key_pressed(egui::Key::Enter)))
+                            {
+                                let mut input = self.input.lock();
+                                input.extend(self.input_text.drain(..));
+                                input.push_back('\n');
                             }
                         });
                         let q = self.message_queue.read();
                         for s in q.iter() {
                             ui.label(s);
                         }
+                        let log = self.log_queue.read();
+                        if !log.is_empty() {
+                            egui::CollapsingHeader::new("Log").show(ui, |ui| {
+                                for s in log.iter() {
+                                    ui.monospace(s);
+                                }
+                            });
+                        }
                     });
             });
         egui::TopBottomPanel::bottom("register_overview").show(ctx, |ui| {
@@ -269,7 +586,16 @@ impl eframe::App for SSMRS {
 
         egui::SidePanel::left("side_panel").show(ctx, |ui| {
             egui::ScrollArea::vertical().show(ui, |ui| {
+                for (severity, diagnostic) in &self.diagnostics {
+                    let color = match severity {
+                        Severity::Error => Color32::RED,
+                        Severity::Warning => Color32::YELLOW,
+                    };
+                    ui.label(RichText::new(diagnostic).monospace().color(color));
+                }
                 egui::Grid::new("code_table").show(ui, |ui| {
+                    ui.label(RichText::new("Break").strong());
+                    ui.label(RichText::new("Line").strong());
                     ui.label(RichText::new("Label").strong());
                     ui.label(RichText::new("Address").strong());
                     ui.label(RichText::new("PC").strong());
@@ -282,11 +608,11 @@ impl eframe::App for SSMRS {
                     let mut count = 0;
                     let mut next_label = None;
                     for instr in self.code.iter() {
-                        if let Instr::LABEL(text) = instr {
+                        if let SourceInstr::Label(text) = instr {
                             next_label = Some(text.clone());
                             continue;
                         }
-                        if let Instr::ANNOTE(reg, start, end, color, text) = instr.clone() {
+                        if let SourceInstr::Annote(reg, start, end, color, text) = instr.clone() {
                             self.annotations.insert(
                                 count,
                                 Annote {
@@ -299,6 +625,27 @@ impl eframe::App for SSMRS {
                             );
                             continue;
                         }
+                        // breakpoint
+                        let addr = count as i32;
+                        let mut enabled = self.breakpoints.contains(&addr);
+                        if ui.checkbox(&mut enabled, "").changed() {
+                            if enabled {
+                                self.breakpoints.insert(addr);
+                                if let Some(cpu) = &mut self.cpu {
+                                    cpu.add_breakpoint(addr);
+                                }
+                            } else {
+                                self.breakpoints.remove(&addr);
+                                if let Some(cpu) = &mut self.cpu {
+                                    cpu.remove_breakpoint(addr);
+                                }
+                            }
+                        }
+                        // line
+                        match self.source_map.get(addr) {
+                            Some(location) => ui.label(location.line.to_string()),
+                            None => ui.label(""),
+                        };
                         // label
                         if let Some(label) = next_label {
                             ui.label(label);
@@ -312,7 +659,7 @@ impl eframe::App for SSMRS {
                         if self
                             .cpu
                             .as_ref()
-                            .map_or(false, |cpu| cpu.read_registers().pc == (count as i32))
+                            .is_some_and(|cpu| cpu.read_registers().pc == (count as i32))
                         {
                             ui.radio(true, "").clicked();
                         } else {
@@ -326,8 +673,11 @@ impl eframe::App for SSMRS {
                             ui.label("");
                         }
                         // instr
-                        for l in instr.name_and_params() {
-                            ui.label(l);
+                        for (i, l) in instr.name_and_params().into_iter().enumerate() {
+                            let label = ui.label(l);
+                            if let (0, SourceInstr::Instr(instr)) = (i, instr) {
+                                label.on_hover_text(instr.info().description);
+                            }
                         }
                         ui.end_row();
                         count += instr.instr_size();
@@ -346,7 +696,8 @@ impl eframe::App for SSMRS {
                     ui.end_row();
 
                     if let Some(cpu) = self.cpu.as_ref() {
-                        self.max_sp = self.max_sp.max(cpu.read_registers().sp as usize);
+                        let sp = cpu.read_registers().sp.max(0) as usize;
+                        self.max_sp = self.max_sp.max(sp).min(cpu.read_memory().len() - 1);
                         let start = self.initial_sp;
                         let end = self.max_sp;
                         for i in start..=end {
@@ -377,6 +728,59 @@ impl eframe::App for SSMRS {
     }
 }
 
+fn settings_ui(ui: &mut Ui, config: &mut CpuConfig, max_steps: &mut u64) {
+    egui::Grid::new("settings_grid").show(ui, |ui| {
+        ui.label("Stack size");
+        ui.add(egui::DragValue::new(&mut config.stack_size));
+        ui.end_row();
+        ui.label("Heap start");
+        ui.add(egui::DragValue::new(&mut config.heap_start));
+        ui.end_row();
+        ui.label("Heap limit");
+        ui.add(egui::DragValue::new(&mut config.heap_limit));
+        ui.end_row();
+        ui.label("Read-only code");
+        ui.checkbox(&mut config.protect_code, "");
+        ui.end_row();
+        ui.label("Opcodes");
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut config.encoding, Encoding::Ssmrs, "SSMrs");
+            ui.radio_value(&mut config.encoding, Encoding::Java, "Java SSM");
+        });
+        ui.end_row();
+        ui.label("History limit");
+        ui.add(egui::DragValue::new(&mut config.history_limit));
+        ui.end_row();
+        ui.label("Run step limit (0 for none)");
+        ui.add(egui::DragValue::new(max_steps));
+        ui.end_row();
+        for r in 0..8 {
+            let Ok(reg) = Reg::try_from(r) else {
+                continue;
+            };
+            let mut enabled = config.initial_registers.contains_key(&reg);
+            if ui
+                .checkbox(&mut enabled, format!("Initial {}", reg))
+                .changed()
+            {
+                if enabled {
+                    config.initial_registers.insert(reg, 0);
+                } else {
+                    config.initial_registers.remove(&reg);
+                }
+            }
+            if let Some(value) = config.initial_registers.get_mut(&reg) {
+                ui.add(egui::DragValue::new(value));
+            }
+            ui.end_row();
+        }
+    });
+    if let Err(e) = config.validate() {
+        ui.label(RichText::new(e.to_string()).color(Color32::RED));
+    }
+    ui.label("Changes take effect after a reset.");
+}
+
 fn convert_color(color: &Color) -> Color32 {
     use Color::*;
     match color {
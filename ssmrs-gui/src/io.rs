@@ -0,0 +1,70 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use egui::mutex::{Mutex, RwLock};
+use ssmrs::io::{self, Io};
+
+/// Sends trap output and diagnostics to the message queues shown in the GUI, and reads input
+/// typed into the input box.
+pub struct QueueIo {
+    output: Arc<RwLock<Vec<String>>>,
+    log: Arc<RwLock<Vec<String>>>,
+    input: Arc<Mutex<VecDeque<char>>>,
+    line_open: bool,
+}
+
+impl QueueIo {
+    pub fn new(
+        output: Arc<RwLock<Vec<String>>>,
+        log: Arc<RwLock<Vec<String>>>,
+        input: Arc<Mutex<VecDeque<char>>>,
+    ) -> Self {
+        Self {
+            output,
+            log,
+            input,
+            line_open: false,
+        }
+    }
+}
+
+impl Io for QueueIo {
+    fn write_int(&mut self, value: i32) {
+        self.output.write().push(value.to_string());
+        self.line_open = false;
+    }
+
+    fn write_char(&mut self, value: char) {
+        let mut q = self.output.write();
+        if value == '\n' {
+            if !self.line_open {
+                q.push(String::new());
+            }
+            self.line_open = false;
+            return;
+        }
+        match q.last_mut() {
+            Some(line) if self.line_open => line.push(value),
+            _ => q.push(value.to_string()),
+        }
+        self.line_open = true;
+    }
+
+    fn read_int(&mut self) -> Option<i32> {
+        let res = io::read_int(&mut self.input.lock(), |_| false)?;
+        match res {
+            Ok(value) => Some(value),
+            Err(message) => {
+                self.log(&message);
+                None
+            }
+        }
+    }
+
+    fn read_char(&mut self) -> Option<char> {
+        io::read_char(&mut self.input.lock(), |_| false)
+    }
+
+    fn log(&mut self, message: &str) {
+        self.log.write().push(message.to_string());
+    }
+}
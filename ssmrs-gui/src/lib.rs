@@ -1,2 +1,3 @@
 mod app;
+mod io;
 pub use app::SSMRS;
@@ -0,0 +1,490 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    cpu::LoadError,
+    diagnostic::{Diagnostic, Severity, Sources},
+    expand::expand,
+    instruction::{AsmItem, Operand, SourceInstr},
+    parser::parse_spanned,
+    source_map::SourceMap,
+    Code,
+};
+
+/// How deeply files may include other files.
+const MAX_INCLUDE_DEPTH: usize = 64;
+
+/// Code that assembled without errors, along with any warnings found on the way.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Assembly {
+    pub code: Code,
+    pub source_map: SourceMap,
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Reads an included file.
+pub type Reader = Box<dyn Fn(&Path) -> std::io::Result<String>>;
+
+/// Assembles programs that consist of several files.
+pub struct Assembler {
+    /// Directories to look for included files in, after the directory of the including file.
+    pub include_paths: Vec<PathBuf>,
+    read: Reader,
+    sources: Sources,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Assembler {
+    /// An assembler that reads included files from the file system.
+    pub fn new() -> Self {
+        Self::with_reader(|path| std::fs::read_to_string(path))
+    }
+
+    /// An assembler that reads included files with `read`.
+    pub fn with_reader(read: impl Fn(&Path) -> std::io::Result<String> + 'static) -> Self {
+        Self {
+            include_paths: Vec::new(),
+            read: Box::new(read),
+            sources: Sources::default(),
+        }
+    }
+
+    /// Every file read so far, which the spans of the diagnostics refer to.
+    pub fn sources(&self) -> &Sources {
+        &self.sources
+    }
+
+    /// Assembles the given files, named and with their contents, one after the other into one
+    /// program. Then expands macros and constants and checks the labels. On failure, the
+    /// returned diagnostics include the warnings as well as the errors.
+    pub fn assemble(&mut self, files: &[(&str, &str)]) -> Result<Assembly, Vec<Diagnostic>> {
+        let mut items = Vec::new();
+        let mut diagnostics = Vec::new();
+        for (name, text) in files {
+            let mut stack = vec![normalize(Path::new(name))];
+            self.add_file(name, text, &mut stack, &mut items, &mut diagnostics);
+        }
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        let (code, mut diagnostics) = expand(items);
+        diagnostics.extend(check_labels(&code, &self.sources));
+        diagnostics.sort_by_key(|d| d.span.start);
+        if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+            return Err(diagnostics);
+        }
+        Ok(Assembly {
+            source_map: SourceMap::new(&self.sources, &code),
+            code: code.into_iter().map(|(instr, _)| instr).collect(),
+            warnings: diagnostics,
+        })
+    }
+
+    /// Parses a file and adds its items to `items`, with those of the files it includes in
+    /// place of the includes. `stack` holds the file and the files that include it.
+    fn add_file(
+        &mut self,
+        name: &str,
+        text: &str,
+        stack: &mut Vec<PathBuf>,
+        items: &mut Vec<(AsmItem, Range<usize>)>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) {
+        let start = self.sources.add(name, text);
+        let shift = |span: Range<usize>| span.start + start..span.end + start;
+        let parsed = match parse_spanned(text) {
+            Ok(parsed) => parsed,
+            Err(errors) => {
+                diagnostics.extend(errors.into_iter().map(|e| Diagnostic {
+                    span: shift(e.span.clone()),
+                    ..e
+                }));
+                return;
+            }
+        };
+        for (item, span) in parsed {
+            let span = shift(span);
+            let AsmItem::Include(include) = item else {
+                items.push((item, span));
+                continue;
+            };
+            let Some((path, text)) = self.find(&include, stack) else {
+                diagnostics.push(Diagnostic::error(
+                    span,
+                    format!("cannot find `{}` to include", include),
+                ));
+                continue;
+            };
+            if stack.contains(&path) {
+                diagnostics.push(Diagnostic::error(
+                    span,
+                    format!("`{}` includes itself", path.display()),
+                ));
+            } else if stack.len() >= MAX_INCLUDE_DEPTH {
+                diagnostics.push(Diagnostic::error(span, "includes are nested too deeply"));
+            } else {
+                stack.push(path.clone());
+                self.add_file(
+                    &path.display().to_string(),
+                    &text,
+                    stack,
+                    items,
+                    diagnostics,
+                );
+                stack.pop();
+            }
+        }
+    }
+
+    /// Looks for an included file next to the including file, and then in the include paths.
+    fn find(&self, include: &str, stack: &[PathBuf]) -> Option<(PathBuf, String)> {
+        let dir = stack
+            .last()
+            .and_then(|file| file.parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        std::iter::once(dir)
+            .chain(self.include_paths.iter().cloned())
+            .map(|dir| normalize(&dir.join(include)))
+            .find_map(|path| (self.read)(&path).ok().map(|text| (path, text)))
+    }
+}
+
+/// Removes `.` and `dir/..` from a path without touching the file system, so that a file has one
+/// name however it is included.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Assembles a single file. `file` names the file in the source map and is used to find the
+/// files it includes.
+pub fn assemble(file: &str, source: &str) -> Result<Assembly, Vec<Diagnostic>> {
+    Assembler::new().assemble(&[(file, source)])
+}
+
+/// Reports branches to undefined labels and labels that are defined twice as errors, and labels
+/// that are never used as warnings, except `main` and labels at address 0, where programs start.
+pub fn check_labels(code: &[(SourceInstr, Range<usize>)], sources: &Sources) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut defined: HashMap<&str, Range<usize>> = HashMap::new();
+    let mut used = HashSet::new();
+    let mut addr = 0;
+    for (instr, span) in code {
+        if let SourceInstr::Label(name) = instr {
+            if name == "main" || addr == 0 {
+                used.insert(name.as_str());
+            }
+            if let Some(first) = defined.get(name.as_str()) {
+                let place = match (sources.locate(first.start), sources.file(span.start)) {
+                    (Some((file, line, _)), Some(here)) if file.start == here.start => {
+                        format!("on line {}", line)
+                    }
+                    (Some((file, line, _)), _) => format!("in {} on line {}", file.name, line),
+                    (None, _) => "before".to_string(),
+                };
+                diagnostics.push(Diagnostic::error(
+                    span.clone(),
+                    format!("label `{}` is already defined {}", name, place),
+                ));
+            } else {
+                defined.insert(name.as_str(), span.clone());
+            }
+        }
+        addr += instr.instr_size();
+    }
+
+    for (instr, span) in code {
+        for name in references(instr) {
+            if defined.contains_key(name) {
+                used.insert(name);
+            } else {
+                diagnostics.push(Diagnostic::error(
+                    span.clone(),
+                    format!("undefined label `{}`", name),
+                ));
+            }
+        }
+    }
+
+    for (name, span) in defined {
+        if !used.contains(name) {
+            diagnostics.push(Diagnostic::warning(
+                span,
+                format!("label `{}` is never used", name),
+            ));
+        }
+    }
+    diagnostics.sort_by_key(|d| d.span.start);
+    diagnostics
+}
+
+/// The labels an instruction refers to.
+fn references(instr: &SourceInstr) -> Vec<&str> {
+    match instr {
+        SourceInstr::Unresolved(unresolved) => unresolved
+            .operands()
+            .into_iter()
+            .flat_map(Operand::labels)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Replaces label operands with addresses and removes the labels. Branches get the offset to
+/// the label, all other instructions its absolute address.
+pub(crate) fn resolve_labels(code: &mut Code) -> Result<(), LoadError> {
+    let mut labels = HashMap::new();
+    let mut addr = 0i32;
+    for instr in code.iter() {
+        if let SourceInstr::Label(n) = instr {
+            if labels.insert(n.clone(), addr).is_some() {
+                return Err(LoadError::DuplicateLabel(n.clone()));
+            }
+        }
+        addr += instr.instr_size() as i32;
+    }
+    let lookup = |name: &str| labels.get(name).copied();
+
+    let mut addr = 0i32;
+    for instr in code.iter_mut() {
+        let next = addr + instr.instr_size() as i32;
+        if let SourceInstr::Unresolved(unresolved) = instr {
+            let relative = unresolved.is_branch();
+            let resolved = unresolved.resolve(|operand| {
+                let value = operand.eval(&lookup).map_err(LoadError::UndefinedLabel)?;
+                Ok(if relative {
+                    value.wrapping_sub(next)
+                } else {
+                    value
+                })
+            })?;
+            *instr = resolved;
+        }
+        addr = next;
+    }
+    code.retain(|instr| !matches!(instr, SourceInstr::Label(_)));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+    };
+
+    use super::{assemble, Assembler};
+    use crate::{
+        cpu::{LoadError, StepOutcome},
+        diagnostic::line_col,
+        instruction::{Expr, Operand, Unresolved, UnresolvedInstr},
+        io::BufferIo,
+        Code, Cpu, Instr, Parser, SourceInstr,
+    };
+
+    fn messages(src: &str, diagnostics: &[crate::diagnostic::Diagnostic]) -> Vec<String> {
+        diagnostics
+            .iter()
+            .map(|d| {
+                let (line, col) = line_col(src, d.span.start);
+                format!("{}:{}: {}: {}", line, col, d.severity, d.message)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn checks_labels() {
+        let src = "main: LDC 1\nloop: BRF done\n  BRA lable\nloop: BSR loop\ndone: HALT";
+        let errors = assemble("test.ssm", src).unwrap_err();
+        assert_eq!(
+            messages(src, &errors),
+            [
+                "3:3: error: undefined label `lable`",
+                "4:1: error: label `loop` is already defined on line 2",
+            ]
+        );
+
+        let src = "start: LDC 1\nskip: BRT end\nend: HALT\nmain: HALT";
+        let assembly = assemble("test.ssm", src).unwrap();
+        assert_eq!(
+            messages(src, &assembly.warnings),
+            ["2:1: warning: label `skip` is never used"]
+        );
+        assert_eq!(assembly.code.len(), 8);
+    }
+
+    #[test]
+    fn label_operands() {
+        let src = "LDC func\nJSR\nLDC table+1\nLDA 0\nTRAP 0\nBRA end-2\nfunc: LDC 7\nTRAP 0\nRET\ntable: LDC 42\nend: LDC end-table\nTRAP 0\nHALT";
+        let mut code = assemble("test.ssm", src).unwrap().code;
+        assert_eq!(
+            code[0],
+            SourceInstr::Unresolved(Unresolved::Instr(UnresolvedInstr::LDC(Operand::Expr(
+                Expr::Label("func".to_string())
+            ))))
+        );
+        super::resolve_labels(&mut code).unwrap();
+        assert_eq!(code[0], Instr::LDC(11).into());
+        assert_eq!(code[5], Instr::BRA(5).into());
+
+        let io = BufferIo::new();
+        let mut cpu = Cpu::new(0, Box::new(io.clone()));
+        cpu.load_code(crate::parse().parse(src).unwrap()).unwrap();
+        while cpu.step() == Ok(StepOutcome::Running) {}
+        assert_eq!(io.output(), "7\n42\n2\n");
+
+        let src = "main: LDC main+nowhere\nHALT";
+        assert_eq!(
+            messages(src, &assemble("test.ssm", src).unwrap_err()),
+            ["1:7: error: undefined label `nowhere`"]
+        );
+    }
+
+    #[test]
+    fn includes() {
+        let files = HashMap::from([
+            (
+                "lib/print.ssm",
+                ".include \"../lib/consts.ssm\"\nprint: LDS -1\nTRAP 0\nRET",
+            ),
+            ("lib/consts.ssm", ".equ ANSWER 42"),
+            ("shared/loop.ssm", ".include \"loop.ssm\""),
+        ]);
+        let reader = move |path: &Path| {
+            let path = path.to_str().unwrap().replace('\\', "/");
+            files
+                .get(path.as_str())
+                .map(|text| text.to_string())
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))
+        };
+        let mut assembler = Assembler::with_reader(reader);
+        assembler.include_paths.push(PathBuf::from("lib"));
+        let main = ".include \"print.ssm\"\nLDC ANSWER\nBSR print\nHALT";
+        let data = "LDC 1\n  BSR print";
+        let assembly = assembler
+            .assemble(&[("main.ssm", main), ("data.ssm", data)])
+            .unwrap();
+        let files = assembly
+            .source_map
+            .iter()
+            .map(|(_, location)| location.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [
+                "lib/print.ssm:2:8",
+                "lib/print.ssm:3:1",
+                "lib/print.ssm:4:1",
+                "main.ssm:2:1",
+                "main.ssm:3:1",
+                "main.ssm:4:1",
+                "data.ssm:1:1",
+                "data.ssm:2:3",
+            ]
+        );
+        assert_eq!(assembly.code[4], Instr::LDC(42).into());
+
+        let src = ".include \"missing.ssm\"\n.include \"shared/loop.ssm\"";
+        let errors = assembler.assemble(&[("cycle.ssm", src)]).unwrap_err();
+        let messages = errors
+            .iter()
+            .map(|e| {
+                assembler
+                    .sources()
+                    .render(e)
+                    .lines()
+                    .take(2)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                "error: cannot find `missing.ssm` to include  --> cycle.ssm:1:1",
+                "error: `shared/loop.ssm` includes itself  --> shared/loop.ssm:1:1",
+            ]
+        );
+    }
+
+    #[test]
+    fn data_directives() {
+        let src = ".equ N 2\nLDC table\nHALT\ntable: .word table, 'a' , -N\n.string \"a\\\"\"\n.stringz \"\"\n.space N+1";
+        let mut code = assemble("test.ssm", src).unwrap().code;
+        assert_eq!(code[3].to_string(), ".word table, 97, -2");
+        super::resolve_labels(&mut code).unwrap();
+        assert_eq!(
+            code,
+            [
+                Instr::LDC(3).into(),
+                Instr::HALT.into(),
+                SourceInstr::Word(vec![3, 97, -2]),
+                SourceInstr::Word(vec![97, 34]),
+                SourceInstr::Word(vec![0]),
+                SourceInstr::Space(3),
+            ]
+        );
+
+        let src = "a: .space a\n.space -1\nLDC a";
+        assert_eq!(
+            messages(src, &assemble("test.ssm", src).unwrap_err()),
+            [
+                "1:4: error: the size of `.space` must be a constant",
+                "2:1: error: the size of `.space` cannot be negative",
+            ]
+        );
+        let src = ".word 1,\n.string 'a'";
+        assert_eq!(
+            messages(src, &assemble("test.ssm", src).unwrap_err()),
+            [
+                "1:8: error: expected operand after `,`",
+                "2:9: error: unexpected '\\'', expected string",
+            ]
+        );
+    }
+
+    #[test]
+    fn load_rejects_undefined_labels() {
+        let mut cpu = Cpu::new(0, Box::new(BufferIo::new()));
+        let code: Code = crate::parse().parse("BRA nowhere").unwrap();
+        assert_eq!(
+            cpu.load_code(code),
+            Err(LoadError::UndefinedLabel("nowhere".to_string()))
+        );
+        let code: Code = crate::parse().parse("a: NOP\na: BRA a").unwrap();
+        assert_eq!(
+            cpu.load_code(code),
+            Err(LoadError::DuplicateLabel("a".to_string()))
+        );
+
+        let mut code = crate::parse().parse("a: BRA b\nNOP\nb: BRT a").unwrap();
+        super::resolve_labels(&mut code).unwrap();
+        assert_eq!(
+            code,
+            [Instr::BRA(1), Instr::NOP, Instr::BRT(-5)].map(SourceInstr::Instr)
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{instruction::Encoding, register::Reg};
+
+pub const DEFAULT_STACK_SIZE: usize = 2000;
+pub const DEFAULT_HEAP_SIZE: usize = 1 << 20;
+pub const DEFAULT_HISTORY_LIMIT: usize = 10_000;
+
+/// Memory layout and initial state of a [`Cpu`](crate::Cpu).
+///
+/// Code is loaded at address 0 and the stack grows upwards right after it, up to
+/// `stack_size`. The heap occupies the addresses from `heap_start` up to (but not including)
+/// `heap_limit`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuConfig {
+    pub stack_size: usize,
+    pub heap_start: usize,
+    pub heap_limit: usize,
+    /// Makes the loaded code read-only, so stray stores fault instead of overwriting it.
+    pub protect_code: bool,
+    /// Number of steps that can be undone with [`Cpu::step_back`](crate::Cpu::step_back). Zero
+    /// disables recording.
+    pub history_limit: usize,
+    /// Register values set after loading the code, overriding the defaults.
+    pub initial_registers: BTreeMap<Reg, i32>,
+    /// The opcodes the code is loaded and executed with.
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+impl Default for CpuConfig {
+    fn default() -> Self {
+        Self {
+            stack_size: DEFAULT_STACK_SIZE,
+            heap_start: DEFAULT_STACK_SIZE,
+            heap_limit: DEFAULT_STACK_SIZE + DEFAULT_HEAP_SIZE,
+            protect_code: false,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+            initial_registers: BTreeMap::new(),
+            encoding: Encoding::default(),
+        }
+    }
+}
+
+impl CpuConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.stack_size == 0 {
+            Err(ConfigError::EmptyStack)
+        } else if self.heap_start < self.stack_size {
+            Err(ConfigError::HeapOverlapsStack)
+        } else if self.heap_limit < self.heap_start {
+            Err(ConfigError::HeapLimitBeforeStart)
+        } else if self.heap_limit > i32::MAX as usize {
+            Err(ConfigError::TooLarge)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConfigError {
+    EmptyStack,
+    HeapOverlapsStack,
+    HeapLimitBeforeStart,
+    TooLarge,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::EmptyStack => write!(f, "the stack size must be at least 1"),
+            ConfigError::HeapOverlapsStack => write!(f, "the heap must start after the stack"),
+            ConfigError::HeapLimitBeforeStart => {
+                write!(f, "the heap limit must not be before the heap start")
+            }
+            ConfigError::TooLarge => write!(f, "memory must fit in {} words", i32::MAX),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
@@ -1,80 +1,380 @@
 use std::{
-    cmp::{max, min},
-    collections::HashMap,
-    fmt::Formatter,
+    cell::RefCell,
+    cmp::min,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt::{Display, Formatter},
 };
 
 use crate::{
-    instruction::Instr,
+    assembler::{resolve_labels, Assembly},
+    config::{ConfigError, CpuConfig},
+    debug::{Access, Budget, RunLimits, StopReason, WatchKind, WatchTarget, Watchpoint},
+    fault::{CpuFault, FaultKind},
+    instruction::{Encoding, Instr, Operand, SourceInstr},
+    io::Io,
+    object::Object,
     register::{Reg, RegisterFile},
-    Code, MAX_STACK_SIZE,
+    snapshot::{Snapshot, SnapshotError, SNAPSHOT_VERSION},
+    source_map::SourceMap,
+    Code,
 };
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StepOutcome {
+    Running,
+    Halted,
+    /// An input trap found no input; the trap is retried on the next step.
+    WaitingForInput,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum LoadError {
+    CodeTooLarge { code_size: usize, stack_size: usize },
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    WrongEncoding { object: Encoding, cpu: Encoding },
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::CodeTooLarge {
+                code_size,
+                stack_size,
+            } => write!(
+                f,
+                "the program needs {} words, but the stack size is only {}",
+                code_size, stack_size
+            ),
+            LoadError::UndefinedLabel(name) => write!(f, "undefined label `{}`", name),
+            LoadError::DuplicateLabel(name) => write!(f, "label `{}` is defined twice", name),
+            LoadError::WrongEncoding { object, cpu } => write!(
+                f,
+                "the program uses the {} encoding, but the machine uses {}",
+                object, cpu
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// The region an address belongs to. Code occupies the start of memory, followed by the stack
+/// up to `stack_size`; the heap is configured separately and may be preceded by a gap.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Segment {
+    Code,
+    Stack,
+    Heap,
+}
+
+/// Everything needed to undo or redo a single step.
+#[derive(Clone, Debug)]
+struct UndoEntry {
+    before: RegisterFile,
+    after: RegisterFile,
+    /// Address, old value and new value of every memory write, in order.
+    writes: Vec<(i32, i32, i32)>,
+    heap_len: usize,
+}
+
+impl UndoEntry {
+    /// Register reads and memory reads are not recorded, so read watchpoints never match.
+    fn touches(&self, watchpoint: &Watchpoint) -> bool {
+        match (watchpoint.target, watchpoint.kind) {
+            (_, WatchKind::Read) => false,
+            (WatchTarget::Register(reg), _) => self.before[reg] != self.after[reg],
+            (WatchTarget::Memory(addr), kind) => self
+                .writes
+                .iter()
+                .any(|&(a, old, new)| a == addr && (kind == WatchKind::Write || old != new)),
+        }
+    }
+}
+
 // #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Cpu {
-    memory: [i32; MAX_STACK_SIZE],
+    config: CpuConfig,
+    memory: Vec<i32>,
     registers: RegisterFile,
     verbosity: u8,
-    write: Box<dyn Fn(String)>,
+    io: Box<dyn Io>,
     heap: Vec<i32>,
+    code_size: usize,
+    instructions: u64,
+    labels: BTreeMap<String, i32>,
+    source_map: SourceMap,
+    breakpoints: BTreeSet<i32>,
+    watchpoints: Vec<Watchpoint>,
+    /// Accesses made by the current step, only recorded while there are watchpoints.
+    accesses: RefCell<Vec<Access>>,
+    /// The breakpoint `run_until` last stopped at, which is not hit again when resuming.
+    resume_from: Option<i32>,
+    history: VecDeque<UndoEntry>,
+    /// Steps undone by `step_back`, most recently undone last.
+    undone: Vec<UndoEntry>,
+    /// Memory writes of the current step, which are undone when it faults.
+    writes: Vec<(i32, i32, i32)>,
 }
 
 impl std::fmt::Debug for Cpu {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Cpu")
+            .field("config", &self.config)
             .field("memory", &self.memory)
             .field("registers", &self.registers)
             .field("verbosity", &self.verbosity)
+            .field("code_size", &self.code_size)
+            .field("breakpoints", &self.breakpoints)
+            .field("watchpoints", &self.watchpoints)
             .finish()
     }
 }
 
 impl Cpu {
-    pub fn new(verbosity: u8, write: Box<dyn Fn(String)>) -> Cpu {
-        Cpu {
-            memory: [0; MAX_STACK_SIZE],
+    pub fn new(verbosity: u8, io: Box<dyn Io>) -> Cpu {
+        Self::build(CpuConfig::default(), verbosity, io)
+    }
+
+    pub fn with_config(
+        config: CpuConfig,
+        verbosity: u8,
+        io: Box<dyn Io>,
+    ) -> Result<Cpu, ConfigError> {
+        config.validate()?;
+        Ok(Self::build(config, verbosity, io))
+    }
+
+    fn build(config: CpuConfig, verbosity: u8, io: Box<dyn Io>) -> Cpu {
+        let mut cpu = Cpu {
+            memory: vec![0; config.stack_size],
             registers: RegisterFile::new(),
+            config,
             verbosity,
-            write,
+            io,
             heap: Vec::new(),
+            code_size: 0,
+            instructions: 0,
+            labels: BTreeMap::new(),
+            source_map: SourceMap::default(),
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            accesses: RefCell::new(Vec::new()),
+            resume_from: None,
+            history: VecDeque::new(),
+            undone: Vec::new(),
+            writes: Vec::new(),
+        };
+        cpu.set_reg(Reg::HP, cpu.config.heap_start as i32);
+        cpu.apply_initial_registers();
+        cpu
+    }
+
+    /// Creates a cpu in the state of `snapshot`, with its own layout.
+    pub fn from_snapshot(
+        snapshot: Snapshot,
+        verbosity: u8,
+        io: Box<dyn Io>,
+    ) -> Result<Cpu, SnapshotError> {
+        let mut cpu = Self::build(snapshot.config.clone(), verbosity, io);
+        cpu.restore(snapshot)?;
+        Ok(cpu)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            config: self.config.clone(),
+            registers: self.registers,
+            memory: self.memory.clone(),
+            heap: self.heap.clone(),
+            code_size: self.code_size,
+            instructions: self.instructions,
+            labels: self.labels.clone(),
+            breakpoints: self.breakpoints.clone(),
         }
     }
 
-    pub fn load_code(&mut self, mut code: Code) {
+    /// Replaces the whole machine state, including the layout, with that of `snapshot`. The step
+    /// history is cleared, and a breakpoint at the restored PC is not hit before the first step.
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), SnapshotError> {
+        snapshot.validate()?;
+        self.config = snapshot.config;
+        self.registers = snapshot.registers;
+        self.memory = snapshot.memory;
+        self.heap = snapshot.heap;
+        self.code_size = snapshot.code_size;
+        self.instructions = snapshot.instructions;
+        self.labels = snapshot.labels;
+        self.source_map = SourceMap::default();
+        self.breakpoints = snapshot.breakpoints;
+        self.resume_from = Some(self.registers.pc);
+        self.history.clear();
+        self.undone.clear();
+        Ok(())
+    }
+
+    fn apply_initial_registers(&mut self) {
+        for (&reg, &val) in &self.config.initial_registers {
+            self.registers[reg] = val;
+        }
+    }
+
+    pub fn config(&self) -> &CpuConfig {
+        &self.config
+    }
+
+    pub fn load_code(&mut self, mut code: Code) -> Result<(), LoadError> {
         let mut c = 0;
+        self.labels.clear();
+        self.source_map = SourceMap::default();
         for i in &code {
             if self.verbosity > 0 {
-                (self.write)(format!("{}: {:?}", c, i));
+                self.io.log(&format!("{}: {}", c, i));
+            }
+            if let SourceInstr::Label(name) = i {
+                self.labels.insert(name.clone(), c as i32);
             }
             c += i.instr_size();
         }
-        fix_jumps(&mut code);
+        resolve_labels(&mut code)?;
         let mut c = 0;
         for i in &code {
             if self.verbosity > 0 {
-                (self.write)(format!("{}: {:?}", c, i));
+                self.io.log(&format!("{}: {}", c, i));
             }
             c += i.instr_size();
         }
         remove_annote(&mut code);
-        let code = convert_code(&code);
-        for (i, v) in code.iter().enumerate() {
-            self.memory[i] = *v;
+        // Checked before converting, since `.space` can ask for any amount of memory.
+        let code_size = code.iter().map(SourceInstr::instr_size).sum::<usize>();
+        if code_size > self.memory.len() {
+            return Err(LoadError::CodeTooLarge {
+                code_size,
+                stack_size: self.memory.len(),
+            });
+        }
+        let code = convert_code(&code, self.config.encoding)?;
+        self.load_words(&code, 0);
+        Ok(())
+    }
+
+    /// Loads an assembled program, with its symbols as labels.
+    pub fn load_object(&mut self, object: Object) -> Result<(), LoadError> {
+        if object.encoding != self.config.encoding {
+            return Err(LoadError::WrongEncoding {
+                object: object.encoding,
+                cpu: self.config.encoding,
+            });
+        }
+        if object.words.len() > self.memory.len() {
+            return Err(LoadError::CodeTooLarge {
+                code_size: object.words.len(),
+                stack_size: self.memory.len(),
+            });
         }
-        self.set_reg(Reg::PC, 0);
+        self.labels = object.symbols;
+        self.source_map = object.source_map.unwrap_or_default();
+        self.load_words(&object.words, object.entry);
+        Ok(())
+    }
+
+    fn load_words(&mut self, code: &[i32], entry: i32) {
+        self.memory[..code.len()].copy_from_slice(code);
+        self.code_size = code.len();
+        self.instructions = 0;
+        self.resume_from = None;
+        self.history.clear();
+        self.undone.clear();
+        self.set_reg(Reg::PC, entry);
         self.set_reg(Reg::SP, code.len() as i32);
+        self.apply_initial_registers();
+    }
+
+    /// The loaded code as an object, starting at address 0.
+    pub fn object(&self) -> Object {
+        Object {
+            encoding: self.config.encoding,
+            entry: 0,
+            words: self.memory[..self.code_size].to_vec(),
+            symbols: self.labels.clone(),
+            source_map: (!self.source_map.is_empty()).then(|| self.source_map.clone()),
+        }
+    }
+
+    /// Loads assembled code along with its source map.
+    pub fn load_assembly(&mut self, assembly: Assembly) -> Result<(), LoadError> {
+        self.load_code(assembly.code)?;
+        self.source_map = assembly.source_map;
+        Ok(())
+    }
+
+    /// Source locations of the loaded code, which is empty unless it was loaded with
+    /// [`Cpu::load_assembly`].
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    /// Label addresses of the loaded code.
+    pub fn labels(&self) -> &BTreeMap<String, i32> {
+        &self.labels
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<i32> {
+        &self.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, addr: i32) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Returns the address of the label, or `None` if the loaded code does not define it.
+    pub fn add_label_breakpoint(&mut self, label: &str) -> Option<i32> {
+        let addr = *self.labels.get(label)?;
+        self.add_breakpoint(addr);
+        Some(addr)
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: i32) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    fn record(&self, target: WatchTarget, write: bool, changed: bool) {
+        if !self.watchpoints.is_empty() {
+            self.accesses.borrow_mut().push(Access {
+                target,
+                write,
+                changed,
+            });
+        }
     }
 
     fn get_reg(&self, reg: Reg) -> i32 {
+        self.record(WatchTarget::Register(reg), false, false);
         self.registers[reg]
     }
 
     fn set_reg(&mut self, reg: Reg, val: i32) {
+        self.record(WatchTarget::Register(reg), true, self.registers[reg] != val);
         self.registers[reg] = val;
     }
 
     fn adjust_reg(&mut self, reg: Reg, val: i32) {
-        self.registers[reg] += val;
+        self.set_reg(reg, self.registers[reg].wrapping_add(val));
     }
 
     fn reserve_heap(&mut self, size: usize) {
@@ -84,251 +384,474 @@ impl Cpu {
         }
     }
 
-    fn get_mem(&self, addr: i32) -> i32 {
-        let addr = addr as usize;
-        if addr < MAX_STACK_SIZE {
-            self.memory[addr]
+    /// Number of words taken up by the loaded code.
+    pub fn code_size(&self) -> usize {
+        self.code_size
+    }
+
+    /// Returns `None` for negative addresses and addresses outside of every segment.
+    pub fn segment(&self, addr: i32) -> Option<Segment> {
+        let idx = usize::try_from(addr).ok()?;
+        if idx < self.code_size {
+            Some(Segment::Code)
+        } else if idx < self.config.stack_size {
+            Some(Segment::Stack)
+        } else if (self.config.heap_start..self.config.heap_limit).contains(&idx) {
+            Some(Segment::Heap)
         } else {
-            self.heap
-                .get(addr - MAX_STACK_SIZE)
+            None
+        }
+    }
+
+    fn get_mem(&self, addr: i32) -> Result<i32, FaultKind> {
+        self.record(WatchTarget::Memory(addr), false, false);
+        self.peek_mem(addr)
+    }
+
+    /// Reads memory without recording the access.
+    fn peek_mem(&self, addr: i32) -> Result<i32, FaultKind> {
+        match self.segment(addr) {
+            Some(Segment::Code | Segment::Stack) => Ok(self.memory[addr as usize]),
+            Some(Segment::Heap) => Ok(self
+                .heap
+                .get(addr as usize - self.config.heap_start)
                 .copied()
-                .unwrap_or_default()
+                .unwrap_or_default()),
+            None => Err(FaultKind::BadAddress(addr)),
         }
     }
 
-    fn set_mem(&mut self, addr: i32, val: i32) {
-        let addr = addr as usize;
-        if addr < MAX_STACK_SIZE {
-            self.memory[addr] = val;
-        } else {
-            let heap_idx = addr - MAX_STACK_SIZE;
-            self.reserve_heap(heap_idx + 1);
-            self.heap[heap_idx] = val;
+    fn set_mem(&mut self, addr: i32, val: i32) -> Result<(), FaultKind> {
+        let old = match self.segment(addr) {
+            Some(Segment::Code) if self.config.protect_code => {
+                return Err(FaultKind::ReadOnlyCode(addr))
+            }
+            Some(_) => self.peek_mem(addr)?,
+            None => return Err(FaultKind::BadAddress(addr)),
+        };
+        self.record(WatchTarget::Memory(addr), true, old != val);
+        self.writes.push((addr, old, val));
+        self.store(addr, val);
+        Ok(())
+    }
+
+    /// Writes to a mapped address without any checks or bookkeeping.
+    fn store(&mut self, addr: i32, val: i32) {
+        match self.segment(addr) {
+            Some(Segment::Code | Segment::Stack) => self.memory[addr as usize] = val,
+            Some(Segment::Heap) => {
+                let heap_idx = addr as usize - self.config.heap_start;
+                self.reserve_heap(heap_idx + 1);
+                self.heap[heap_idx] = val;
+            }
+            None => {}
         }
     }
 
-    fn set_mem_reg(&mut self, reg: Reg, val: i32) {
-        self.set_mem(self.get_reg(reg), val);
+    fn set_mem_reg(&mut self, reg: Reg, val: i32) -> Result<(), FaultKind> {
+        self.set_mem(self.get_reg(reg), val)
     }
 
-    fn get_mem_reg(&self, reg: Reg) -> i32 {
+    fn get_mem_reg(&self, reg: Reg) -> Result<i32, FaultKind> {
         self.get_mem(self.get_reg(reg))
     }
 
-    fn copy_mem(&mut self, src: i32, dst: i32, size: i32) {
+    fn copy_mem(&mut self, src: i32, dst: i32, size: i32) -> Result<(), FaultKind> {
         for i in (0..size).rev() {
-            self.set_mem(dst + i, self.get_mem(src + i));
+            let v = self.get_mem(src.wrapping_add(i))?;
+            self.set_mem(dst.wrapping_add(i), v)?;
         }
+        Ok(())
     }
 
     pub fn read_memory(&self) -> &[i32] {
         &self.memory
     }
 
+    /// The part of the heap that has been written to so far, starting at `heap_start`.
+    pub fn read_heap(&self) -> &[i32] {
+        &self.heap
+    }
+
     pub fn read_registers(&self) -> &RegisterFile {
         &self.registers
     }
 
-    pub fn step(&mut self) -> bool {
-        let current_pc = self.get_reg(Reg::PC);
-        let instr = Instr::from(&self.memory[current_pc as usize..current_pc as usize + 3]);
-        if self.verbosity > 1 {
-            (self.write)(format!("Registers: {:?}", self.registers));
-            (self.write)(format!(
-                "Memory: {:?}",
-                &self.memory[0..(self.get_reg(Reg::SP) as usize + 1)]
-            ));
+    /// Executes a single instruction. A faulting instruction has no effect, so registers and
+    /// memory are left as they were before it, with the PC pointing at it.
+    pub fn step(&mut self) -> Result<StepOutcome, CpuFault> {
+        self.accesses.get_mut().clear();
+        self.resume_from = None;
+        self.writes.clear();
+        let before = self.registers;
+        let heap_len = self.heap.len();
+        let current_pc = self.registers[Reg::PC];
+        let res = self.fetch(current_pc).and_then(|instr| {
+            if self.verbosity > 1 {
+                let sp = self.registers[Reg::SP].clamp(-1, self.config.stack_size as i32 - 1);
+                self.io.log(&format!("Registers: {:?}", self.registers));
+                self.io
+                    .log(&format!("Memory: {:?}", &self.memory[0..(sp + 1) as usize]));
+            }
+            if self.verbosity > 0 {
+                match self.source_map.get(current_pc) {
+                    Some(location) => self
+                        .io
+                        .log(&format!("Executing {:?} at {}", instr, location)),
+                    None => self.io.log(&format!("Executing {:?}", instr)),
+                }
+            }
+            self.registers[Reg::PC] = current_pc.wrapping_add(instr.instr_size() as i32);
+            let outcome = self.exec(instr)?;
+            if self.registers[Reg::SP] >= self.config.stack_size as i32 {
+                return Err(FaultKind::StackOverflow);
+            }
+            Ok(outcome)
+        });
+        match res {
+            Err(_) => {
+                for (addr, old, _) in std::mem::take(&mut self.writes).into_iter().rev() {
+                    self.store(addr, old);
+                }
+                self.heap.truncate(heap_len);
+                self.registers = before;
+            }
+            Ok(StepOutcome::WaitingForInput) => self.registers[Reg::PC] = current_pc,
+            Ok(_) => {
+                self.instructions += 1;
+                self.undone.clear();
+                if self.config.history_limit > 0 {
+                    if self.history.len() == self.config.history_limit {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(UndoEntry {
+                        before,
+                        after: self.registers,
+                        writes: std::mem::take(&mut self.writes),
+                        heap_len,
+                    });
+                }
+            }
+        }
+        res.map_err(|kind| CpuFault {
+            pc: current_pc,
+            kind,
+        })
+    }
+
+    /// Number of instructions executed since the code was loaded, not counting one that faulted
+    /// or is waiting for input.
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Number of steps that can be undone.
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Number of undone steps that can be redone.
+    pub fn future_len(&self) -> usize {
+        self.undone.len()
+    }
+
+    /// Undoes the last step, returning `false` if there is no recorded step left. Trap output
+    /// is not taken back and consumed input is not given back.
+    pub fn step_back(&mut self) -> bool {
+        let Some(entry) = self.history.pop_back() else {
+            return false;
+        };
+        for &(addr, old, _) in entry.writes.iter().rev() {
+            self.store(addr, old);
+        }
+        self.heap.truncate(entry.heap_len);
+        self.registers = entry.before;
+        self.instructions = self.instructions.saturating_sub(1);
+        self.resume_from = None;
+        self.undone.push(entry);
+        true
+    }
+
+    /// Redoes the last step undone by [`step_back`](Self::step_back) without repeating its
+    /// traps, returning `false` if there is nothing to redo. Executing a new step clears the
+    /// steps that can be redone.
+    pub fn step_forward(&mut self) -> bool {
+        let Some(entry) = self.undone.pop() else {
+            return false;
+        };
+        for &(addr, _, new) in &entry.writes {
+            self.store(addr, new);
+        }
+        self.registers = entry.after;
+        self.instructions += 1;
+        self.resume_from = None;
+        self.history.push_back(entry);
+        true
+    }
+
+    /// Steps back until the PC reaches a breakpoint, an undone step wrote to a watched location,
+    /// a limit is reached or the history runs out.
+    pub fn run_back_until(&mut self, limits: RunLimits) -> StopReason {
+        let mut budget = Budget::new(limits);
+        loop {
+            if let Some(reason) = budget.exhausted(self.registers[Reg::PC]) {
+                return reason;
+            }
+            let Some(entry) = self.history.back() else {
+                return StopReason::StartOfHistory;
+            };
+            let hit = self.watchpoints.iter().find(|w| entry.touches(w)).copied();
+            let pc = entry.before.pc;
+            self.step_back();
+            budget.spend();
+            if let Some(watchpoint) = hit {
+                return StopReason::Watchpoint { watchpoint, pc };
+            }
+            if self.breakpoints.contains(&pc) {
+                self.resume_from = Some(pc);
+                return StopReason::Breakpoint(pc);
+            }
+        }
+    }
+
+    /// Steps until the program stops by itself, reaches a breakpoint or accesses a watched
+    /// location, or until a limit is reached. Fetching an instruction and advancing the PC past
+    /// it does not count as an access.
+    pub fn run_until(&mut self, limits: RunLimits) -> StopReason {
+        let mut budget = Budget::new(limits);
+        loop {
+            let pc = self.registers[Reg::PC];
+            if let Some(reason) = budget.exhausted(pc) {
+                return reason;
+            }
+            if self.breakpoints.contains(&pc) && self.resume_from != Some(pc) {
+                self.resume_from = Some(pc);
+                return StopReason::Breakpoint(pc);
+            }
+            match self.step() {
+                Ok(StepOutcome::Running) => {}
+                res => return StopReason::from_step(res, self.registers[Reg::PC]),
+            }
+            budget.spend();
+            let accesses = self.accesses.borrow();
+            let hit = self
+                .watchpoints
+                .iter()
+                .find(|w| accesses.iter().any(|a| w.matches(a)));
+            if let Some(&watchpoint) = hit {
+                return StopReason::Watchpoint { watchpoint, pc };
+            }
         }
-        if self.verbosity > 0 {
-            (self.write)(format!("Executing {:?}", instr));
+    }
+
+    fn fetch(&self, pc: i32) -> Result<Instr, FaultKind> {
+        let start = usize::try_from(pc)
+            .ok()
+            .filter(|&pc| pc < self.memory.len())
+            .ok_or(FaultKind::PcOutOfBounds)?;
+        let end = min(start + 3, self.memory.len());
+        Instr::decode(&self.memory[start..end], self.config.encoding)
+    }
+
+    /// Checks that `words` more values fit on the stack, before anything is written past its end.
+    fn reserve_stack(&self, words: i32) -> Result<(), FaultKind> {
+        if self.get_reg(Reg::SP) as i64 + words as i64 >= self.config.stack_size as i64 {
+            Err(FaultKind::StackOverflow)
+        } else {
+            Ok(())
         }
-        self.set_reg(Reg::PC, current_pc + instr.instr_size() as i32);
-        self.exec(instr)
     }
 
-    fn push_stack(&mut self, value: i32) {
+    fn push_stack(&mut self, value: i32) -> Result<(), FaultKind> {
+        self.reserve_stack(1)?;
         self.adjust_reg(Reg::SP, 1);
-        self.set_mem_reg(Reg::SP, value);
+        self.set_mem_reg(Reg::SP, value)
     }
 
-    fn pop_stack(&mut self) -> i32 {
-        let s = self.get_mem_reg(Reg::SP);
+    fn pop_stack(&mut self) -> Result<i32, FaultKind> {
+        let s = self.get_mem_reg(Reg::SP)?;
         self.adjust_reg(Reg::SP, -1);
-        s
+        Ok(s)
     }
 
-    fn exec(&mut self, i: Instr) -> bool {
+    fn exec(&mut self, i: Instr) -> Result<StepOutcome, FaultKind> {
         match i {
             Instr::STR(reg) => {
-                let v = self.pop_stack();
+                let v = self.pop_stack()?;
                 self.set_reg(reg, v);
             }
             Instr::STL(rel) => {
-                let addr = self.get_reg(Reg::MP) + rel;
-                let val = self.pop_stack();
-                self.set_mem(addr, val);
+                let addr = self.get_reg(Reg::MP).wrapping_add(rel);
+                let val = self.pop_stack()?;
+                self.set_mem(addr, val)?;
             }
             Instr::STS(rel) => {
-                let addr = self.get_reg(Reg::SP) + rel;
-                let val = self.pop_stack();
-                self.set_mem(addr, val);
+                let addr = self.get_reg(Reg::SP).wrapping_add(rel);
+                let val = self.pop_stack()?;
+                self.set_mem(addr, val)?;
             }
             Instr::STA(rel) => {
-                let addr = self.pop_stack();
-                let value = self.pop_stack();
-                self.set_mem(addr + rel, value);
+                let addr = self.pop_stack()?;
+                let value = self.pop_stack()?;
+                self.set_mem(addr.wrapping_add(rel), value)?;
             }
             Instr::LDR(reg) => {
                 let v = self.get_reg(reg);
-                self.push_stack(v);
+                self.push_stack(v)?;
             }
             Instr::LDL(rel) => {
-                let addr = self.get_reg(Reg::MP) + rel;
-                let v = self.get_mem(addr);
-                self.push_stack(v);
+                let addr = self.get_reg(Reg::MP).wrapping_add(rel);
+                let v = self.get_mem(addr)?;
+                self.push_stack(v)?;
             }
             Instr::LDS(rel) => {
-                let addr = self.get_reg(Reg::SP) + rel;
-                let v = self.get_mem(addr);
-                self.push_stack(v);
+                let addr = self.get_reg(Reg::SP).wrapping_add(rel);
+                let v = self.get_mem(addr)?;
+                self.push_stack(v)?;
             }
             Instr::LDA(addr) => {
-                let p = self.pop_stack();
-                let v = self.get_mem(p + addr);
-                self.push_stack(v);
+                let p = self.pop_stack()?;
+                let v = self.get_mem(p.wrapping_add(addr))?;
+                self.push_stack(v)?;
             }
             Instr::LDC(n) => {
-                self.push_stack(n);
+                self.push_stack(n)?;
             }
             Instr::LDLA(rel) => {
-                let addr = self.get_reg(Reg::MP) + rel;
-                self.push_stack(addr);
+                let addr = self.get_reg(Reg::MP).wrapping_add(rel);
+                self.push_stack(addr)?;
             }
             Instr::LDSA(rel) => {
-                let addr = self.get_reg(Reg::SP) + rel;
-                self.push_stack(addr);
+                let addr = self.get_reg(Reg::SP).wrapping_add(rel);
+                self.push_stack(addr)?;
             }
             Instr::LDAA(rel) => {
-                let addr = self.pop_stack() + rel;
-                self.push_stack(addr);
+                let addr = self.pop_stack()?.wrapping_add(rel);
+                self.push_stack(addr)?;
             }
             Instr::BRA(rel) => {
-                let addr = self.get_reg(Reg::PC) + rel;
+                let addr = self.get_reg(Reg::PC).wrapping_add(rel);
                 self.set_reg(Reg::PC, addr);
             }
             Instr::BRF(rel) => {
-                let addr = self.get_reg(Reg::PC) + rel;
-                let cond = self.pop_stack();
+                let addr = self.get_reg(Reg::PC).wrapping_add(rel);
+                let cond = self.pop_stack()?;
                 if cond == 0 {
                     self.set_reg(Reg::PC, addr);
                 }
             }
             Instr::BRT(rel) => {
-                let addr = self.get_reg(Reg::PC) + rel;
-                let cond = self.pop_stack();
+                let addr = self.get_reg(Reg::PC).wrapping_add(rel);
+                let cond = self.pop_stack()?;
                 if cond != 0 {
                     self.set_reg(Reg::PC, addr);
                 }
             }
             Instr::BSR(rel) => {
-                let addr = self.get_reg(Reg::PC) + rel;
-                self.push_stack(self.get_reg(Reg::PC));
+                let addr = self.get_reg(Reg::PC).wrapping_add(rel);
+                self.push_stack(self.get_reg(Reg::PC))?;
                 self.set_reg(Reg::PC, addr);
             }
             Instr::ADD => {
-                let b = self.pop_stack();
-                let a = self.pop_stack();
-                self.push_stack(a + b);
+                let b = self.pop_stack()?;
+                let a = self.pop_stack()?;
+                self.push_stack(a.wrapping_add(b))?;
             }
             Instr::SUB => {
-                let b = self.pop_stack();
-                let a = self.pop_stack();
-                self.push_stack(a - b);
+                let b = self.pop_stack()?;
+                let a = self.pop_stack()?;
+                self.push_stack(a.wrapping_sub(b))?;
             }
             Instr::MUL => {
-                let b = self.pop_stack();
-                let a = self.pop_stack();
-                self.push_stack(a * b);
+                let b = self.pop_stack()?;
+                let a = self.pop_stack()?;
+                self.push_stack(a.wrapping_mul(b))?;
             }
             Instr::DIV => {
-                let b = self.pop_stack();
-                let a = self.pop_stack();
-                self.push_stack(a / b);
+                let b = self.pop_stack()?;
+                let a = self.pop_stack()?;
+                if b == 0 {
+                    return Err(FaultKind::DivideByZero);
+                }
+                self.push_stack(a.wrapping_div(b))?;
             }
             Instr::MOD => {
-                let b = self.pop_stack();
-                let a = self.pop_stack();
-                self.push_stack(a % b);
+                let b = self.pop_stack()?;
+                let a = self.pop_stack()?;
+                if b == 0 {
+                    return Err(FaultKind::DivideByZero);
+                }
+                self.push_stack(a.wrapping_rem(b))?;
             }
             Instr::EQ => {
-                let a = self.pop_stack();
-                let b = self.pop_stack();
-                self.push_stack((a == b).get_ssm_value());
+                let a = self.pop_stack()?;
+                let b = self.pop_stack()?;
+                self.push_stack((a == b).get_ssm_value())?;
             }
             Instr::NE => {
-                let a = self.pop_stack();
-                let b = self.pop_stack();
-                self.push_stack((a != b).get_ssm_value());
+                let a = self.pop_stack()?;
+                let b = self.pop_stack()?;
+                self.push_stack((a != b).get_ssm_value())?;
             }
             Instr::LT => {
-                let b = self.pop_stack();
-                let a = self.pop_stack();
-                self.push_stack((a < b).get_ssm_value());
+                let b = self.pop_stack()?;
+                let a = self.pop_stack()?;
+                self.push_stack((a < b).get_ssm_value())?;
             }
             Instr::LE => {
-                let b = self.pop_stack();
-                let a = self.pop_stack();
-                self.push_stack((a <= b).get_ssm_value());
+                let b = self.pop_stack()?;
+                let a = self.pop_stack()?;
+                self.push_stack((a <= b).get_ssm_value())?;
             }
             Instr::GT => {
-                let b = self.pop_stack();
-                let a = self.pop_stack();
-                self.push_stack((a > b).get_ssm_value());
+                let b = self.pop_stack()?;
+                let a = self.pop_stack()?;
+                self.push_stack((a > b).get_ssm_value())?;
             }
             Instr::GE => {
-                let b = self.pop_stack();
-                let a = self.pop_stack();
-                self.push_stack((a >= b).get_ssm_value());
+                let b = self.pop_stack()?;
+                let a = self.pop_stack()?;
+                self.push_stack((a >= b).get_ssm_value())?;
             }
             Instr::NEG => {
-                let a = self.pop_stack();
-                self.push_stack(-a);
+                let a = self.pop_stack()?;
+                self.push_stack(a.wrapping_neg())?;
             }
             Instr::NOT => {
-                let a = self.pop_stack();
-                self.push_stack((a == 0).get_ssm_value());
+                let a = self.pop_stack()?;
+                self.push_stack((a == 0).get_ssm_value())?;
             }
             Instr::RET => {
-                let addr = self.pop_stack();
+                let addr = self.pop_stack()?;
                 self.set_reg(Reg::PC, addr);
             }
             Instr::UNLINK => {
                 let old_mp = self.get_reg(Reg::MP);
                 self.set_reg(Reg::SP, old_mp);
-                let new_mp = self.pop_stack();
+                let new_mp = self.pop_stack()?;
                 self.set_reg(Reg::MP, new_mp);
             }
             Instr::LINK(locals) => {
                 let mp = self.get_reg(Reg::MP);
-                self.push_stack(mp);
+                self.push_stack(mp)?;
                 let sp = self.get_reg(Reg::SP);
                 self.set_reg(Reg::MP, sp);
                 self.adjust_reg(Reg::SP, locals);
             }
             Instr::AJS(rel) => {
                 let sp = self.get_reg(Reg::SP);
-                self.set_reg(Reg::SP, sp + rel);
+                self.set_reg(Reg::SP, sp.wrapping_add(rel));
             }
             Instr::SWP => {
-                let a = self.pop_stack();
-                let b = self.pop_stack();
-                self.push_stack(a);
-                self.push_stack(b);
+                let a = self.pop_stack()?;
+                let b = self.pop_stack()?;
+                self.push_stack(a)?;
+                self.push_stack(b)?;
             }
             Instr::SWPR(reg) => {
-                let a = self.pop_stack();
+                let a = self.pop_stack()?;
                 let b = self.get_reg(reg);
-                self.push_stack(a);
+                self.push_stack(a)?;
                 self.set_reg(reg, b);
             }
             Instr::SWPRR(reg1, reg2) => {
@@ -342,97 +865,125 @@ impl Cpu {
                 self.set_reg(dest, v);
             }
             Instr::JSR => {
-                let addr = self.pop_stack();
-                self.push_stack(self.get_reg(Reg::PC));
+                let addr = self.pop_stack()?;
+                self.push_stack(self.get_reg(Reg::PC))?;
                 self.set_reg(Reg::PC, addr);
             }
             Instr::TRAP(op) => match op {
                 0 => {
-                    let v = self.pop_stack();
-                    (self.write)(format!("{}", v));
+                    let v = self.pop_stack()?;
+                    self.io.write_int(v);
                 }
                 1 => {
-                    let v = self.pop_stack();
+                    let v = self.pop_stack()?;
                     if let Some(chr) = char::from_u32(v as u32) {
-                        (self.write)(format!("{}", chr));
+                        self.io.write_char(chr);
                     }
                 }
-                _ => panic!("Unknown trap: {}", op),
+                10 => match self.io.read_int() {
+                    Some(v) => self.push_stack(v)?,
+                    None => return Ok(StepOutcome::WaitingForInput),
+                },
+                11 => match self.io.read_char() {
+                    Some(c) => self.push_stack(c as i32)?,
+                    None => return Ok(StepOutcome::WaitingForInput),
+                },
+                12 => match self.io.read_line() {
+                    Some(line) => {
+                        self.push_stack(0)?;
+                        for c in line.chars().rev() {
+                            self.push_stack(c as i32)?;
+                        }
+                    }
+                    None => return Ok(StepOutcome::WaitingForInput),
+                },
+                _ => return Err(FaultKind::UnknownTrap(op)),
             },
             Instr::NOP => {}
-            Instr::HALT => return false,
+            Instr::HALT => return Ok(StepOutcome::Halted),
             Instr::AND => {
-                let a = self.pop_stack();
-                let b = self.pop_stack();
-                self.push_stack(a & b);
+                let a = self.pop_stack()?;
+                let b = self.pop_stack()?;
+                self.push_stack(a & b)?;
             }
             Instr::OR => {
-                let a = self.pop_stack();
-                let b = self.pop_stack();
-                self.push_stack(a | b);
+                let a = self.pop_stack()?;
+                let b = self.pop_stack()?;
+                self.push_stack(a | b)?;
             }
             Instr::XOR => {
-                let a = self.pop_stack();
-                let b = self.pop_stack();
-                self.push_stack(a ^ b);
+                let a = self.pop_stack()?;
+                let b = self.pop_stack()?;
+                self.push_stack(a ^ b)?;
             }
             Instr::STH => {
-                let a = self.pop_stack();
+                let a = self.pop_stack()?;
                 let b = self.get_reg(Reg::HP);
-                self.set_mem_reg(Reg::HP, a);
+                self.set_mem_reg(Reg::HP, a)?;
                 self.adjust_reg(Reg::HP, 1);
-                self.push_stack(b);
+                self.push_stack(b)?;
             }
             Instr::LDH(rel) => {
-                let a = self.pop_stack();
-                let b = self.get_mem(a + rel);
-                self.push_stack(b);
+                let a = self.pop_stack()?;
+                let b = self.get_mem(a.wrapping_add(rel))?;
+                self.push_stack(b)?;
             }
             Instr::STMA(rel, size) => {
-                let src = self.get_reg(Reg::SP) - size;
-                let dst = self.get_mem_reg(Reg::SP);
-                self.copy_mem(src, dst + rel, size);
-                self.adjust_reg(Reg::SP, -(size + 1));
+                let src = self.get_reg(Reg::SP).wrapping_sub(size);
+                let dst = self.get_mem_reg(Reg::SP)?;
+                self.copy_mem(src, dst.wrapping_add(rel), size)?;
+                self.adjust_reg(Reg::SP, size.wrapping_add(1).wrapping_neg());
             }
-            Instr::STMH(_size) => {
-                todo!()
+            Instr::STMH(size) => {
+                let src = self.get_reg(Reg::SP).wrapping_sub(size).wrapping_add(1);
+                let hp = self.get_reg(Reg::HP);
+                self.copy_mem(src, hp, size)?;
+                self.adjust_reg(Reg::SP, size.wrapping_neg());
+                self.adjust_reg(Reg::HP, size);
+                self.push_stack(hp.wrapping_add(size).wrapping_sub(1))?;
             }
             Instr::STML(rel, size) => {
-                self.adjust_reg(Reg::SP, -size);
-                let src= self.get_reg(Reg::SP) + 1;
+                self.adjust_reg(Reg::SP, size.wrapping_neg());
+                let src = self.get_reg(Reg::SP).wrapping_add(1);
                 let dst = self.get_reg(Reg::MP);
-                self.copy_mem(src, dst + rel, size);
+                self.copy_mem(src, dst.wrapping_add(rel), size)?;
             }
             Instr::STMS(rel, size) => {
                 let dst = self.get_reg(Reg::SP);
-                self.adjust_reg(Reg::SP, -size);
-                self.copy_mem(dst - size + 1, dst + rel, size);
+                self.adjust_reg(Reg::SP, size.wrapping_neg());
+                let src = dst.wrapping_sub(size).wrapping_add(1);
+                self.copy_mem(src, dst.wrapping_add(rel), size)?;
             }
             Instr::LDMA(rel, size) => {
                 let dst = self.get_reg(Reg::SP);
-                let src = self.get_mem(dst);
-                self.copy_mem(src + rel, dst, size);
-                self.adjust_reg(Reg::SP, size - 1);
+                let src = self.get_mem(dst)?;
+                self.reserve_stack(size.wrapping_sub(1))?;
+                self.copy_mem(src.wrapping_add(rel), dst, size)?;
+                self.adjust_reg(Reg::SP, size.wrapping_sub(1));
             }
-            Instr::LDMH(_rel, _size) => {
-                todo!()
+            Instr::LDMH(rel, size) => {
+                let dst = self.get_reg(Reg::SP);
+                let src = self.get_mem(dst)?;
+                self.reserve_stack(size.wrapping_sub(1))?;
+                self.copy_mem(src.wrapping_add(rel), dst, size)?;
+                self.adjust_reg(Reg::SP, size.wrapping_sub(1));
             }
             Instr::LDML(rel, size) => {
-                let dst = self.get_reg(Reg::SP) + 1;
-                let src = self.get_reg(Reg::MP) + rel;
-                self.copy_mem(src, dst, size);
+                let dst = self.get_reg(Reg::SP).wrapping_add(1);
+                let src = self.get_reg(Reg::MP).wrapping_add(rel);
+                self.reserve_stack(size)?;
+                self.copy_mem(src, dst, size)?;
                 self.adjust_reg(Reg::SP, size);
             }
             Instr::LDMS(rel, size) => {
                 let dst = self.get_reg(Reg::SP);
-                let src = dst + rel;
-                self.copy_mem(src, dst + 1, size);
+                let src = dst.wrapping_add(rel);
+                self.reserve_stack(size)?;
+                self.copy_mem(src, dst.wrapping_add(1), size)?;
                 self.adjust_reg(Reg::SP, size);
             }
-
-            _ => panic!("Invalid instruction!"),
         }
-        true
+        Ok(StepOutcome::Running)
     }
 }
 
@@ -450,71 +1001,480 @@ impl GetSSMValue for bool {
     }
 }
 
-fn fix_jumps(code: &mut Code) {
-    let mut labels = HashMap::new();
-    for (i, instr) in code.iter().enumerate() {
-        if let Instr::LABEL(n) = instr {
-            labels.insert(n.clone(), i);
+fn remove_annote(code: &mut Code) {
+    code.retain(|instr| !matches!(instr, SourceInstr::Annote(_, _, _, _, _)));
+}
+
+/// Code that still refers to labels is reported like an undefined label.
+fn convert_code(code: &Code, encoding: Encoding) -> Result<Vec<i32>, LoadError> {
+    let mut words = Vec::new();
+    for instr in code {
+        if let SourceInstr::Unresolved(unresolved) = instr {
+            let mut labels = unresolved.operands().into_iter().flat_map(Operand::labels);
+            let label = labels.next().unwrap_or_default();
+            return Err(LoadError::UndefinedLabel(label.to_string()));
         }
+        words.extend(instr.encode(encoding).into_iter().flatten());
     }
-    let sizes = code.iter().map(Instr::instr_size).collect::<Vec<_>>();
-    for (current_idx, instr) in code.iter_mut().enumerate() {
-        let current_idx = current_idx + 1;
-        match instr {
-            Instr::Bra(n) => {
-                let target = *labels.get(n).unwrap();
-                let start = min(target, current_idx);
-                let end = max(target, current_idx);
-                let size = sizes[start..end].iter().sum::<usize>() as i32;
-                if target < current_idx {
-                    *instr = Instr::BRA(-size);
-                } else {
-                    *instr = Instr::BRA(size);
-                }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chumsky::Parser;
+
+    use super::{Cpu, LoadError, Segment, StepOutcome};
+    use crate::{
+        config::CpuConfig,
+        debug::{RunLimits, StopReason, WatchKind, WatchTarget, Watchpoint},
+        fault::{CpuFault, FaultKind},
+        instruction::{Encoding, OperandKind, INSTRUCTIONS},
+        io::BufferIo,
+        register::Reg,
+    };
+
+    fn output(src: &str) -> String {
+        output_with_input(src, "")
+    }
+
+    fn output_with_input(src: &str, input: &str) -> String {
+        let io = BufferIo::with_input(input);
+        let mut cpu = Cpu::new(0, Box::new(io.clone()));
+        cpu.load_code(crate::parse().parse(src).unwrap()).unwrap();
+        while cpu.step() == Ok(StepOutcome::Running) {}
+        io.output()
+    }
+
+    fn run(src: &str) -> Result<StepOutcome, CpuFault> {
+        let mut cpu = Cpu::new(0, Box::new(BufferIo::new()));
+        cpu.load_code(crate::parse().parse(src).unwrap()).unwrap();
+        loop {
+            match cpu.step() {
+                Ok(StepOutcome::Running) => {}
+                res => return res,
             }
-            Instr::Brt(n) => {
-                let target = *labels.get(n).unwrap();
-                let start = min(target, current_idx);
-                let end = max(target, current_idx);
-                let size = sizes[start..end].iter().sum::<usize>() as i32;
-                if target < current_idx {
-                    *instr = Instr::BRT(-size);
-                } else {
-                    *instr = Instr::BRT(size);
-                }
+        }
+    }
+
+    #[test]
+    fn divide_by_zero_faults() {
+        let res = run("LDC 1\nLDC 0\nDIV\nHALT");
+        assert_eq!(
+            res,
+            Err(CpuFault {
+                pc: 4,
+                kind: FaultKind::DivideByZero
+            })
+        );
+    }
+
+    #[test]
+    fn stack_effects_match_execution() {
+        for info in INSTRUCTIONS {
+            let Some(effect) = info.stack_effect else {
+                continue;
+            };
+            let operands = info.operands.iter().map(|&kind| match kind {
+                OperandKind::Register => " R5",
+                OperandKind::Number | OperandKind::Offset => " 0",
+            });
+            // Stack addresses, which are also valid as values. `LDH` needs a heap address.
+            let mut setup = "LDR SP\nLDR SP\nLDR SP\n".to_string();
+            if info.mnemonic == "LDH" {
+                setup.push_str("STH\n");
             }
-            Instr::Brf(n) => {
-                let target = *labels.get(n).unwrap();
-                let start = min(target, current_idx);
-                let end = max(target, current_idx);
-                let size = sizes[start..end].iter().sum::<usize>() as i32;
-                if target < current_idx {
-                    *instr = Instr::BRF(-size);
-                } else {
-                    *instr = Instr::BRF(size);
-                }
+            let src = format!("{}{}{}", setup, info.mnemonic, operands.collect::<String>());
+            let mut cpu = Cpu::new(0, Box::new(BufferIo::new()));
+            cpu.load_code(crate::parse().parse(src.as_str()).unwrap())
+                .unwrap();
+            for _ in setup.lines() {
+                cpu.step().unwrap();
             }
-            Instr::Bsr(n) => {
-                let target = *labels.get(n).unwrap();
-                let start = min(target, current_idx);
-                let end = max(target, current_idx);
-                let size = sizes[start..end].iter().sum::<usize>() as i32;
-                if target < current_idx {
-                    *instr = Instr::BSR(-size);
-                } else {
-                    *instr = Instr::BSR(size);
-                }
+            let sp = cpu.read_registers().sp;
+            cpu.step().unwrap();
+            assert_eq!(cpu.read_registers().sp - sp, effect, "{}", src);
+        }
+    }
+
+    #[test]
+    fn faults_leave_state_unchanged() {
+        let mut cpu = Cpu::new(0, Box::new(BufferIo::new()));
+        cpu.load_code(crate::parse().parse("LDC 7\nLDC 0\nDIV").unwrap())
+            .unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let registers = *cpu.read_registers();
+        let memory = cpu.read_memory().to_vec();
+        assert_eq!(cpu.step().unwrap_err().kind, FaultKind::DivideByZero);
+        assert_eq!(*cpu.read_registers(), registers);
+        assert_eq!(cpu.read_memory(), memory);
+        assert_eq!(cpu.instruction_count(), 2);
+        assert_eq!(cpu.history_len(), 2);
+
+        let config = CpuConfig {
+            stack_size: 8,
+            heap_start: 8,
+            ..Default::default()
+        };
+        let mut cpu = Cpu::with_config(config, 0, Box::new(BufferIo::new())).unwrap();
+        cpu.load_code(crate::parse().parse("LDC 5\nSTH\nLINK 9").unwrap())
+            .unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let registers = *cpu.read_registers();
+        let memory = cpu.read_memory().to_vec();
+        assert_eq!(cpu.step().unwrap_err().kind, FaultKind::StackOverflow);
+        assert_eq!(*cpu.read_registers(), registers);
+        assert_eq!(cpu.read_memory(), memory);
+        assert_eq!(cpu.read_heap(), &[5]);
+    }
+
+    #[test]
+    fn waiting_for_input_keeps_redo() {
+        let io = BufferIo::with_input("5\n");
+        let mut cpu = Cpu::new(0, Box::new(io.clone()));
+        cpu.load_code(crate::parse().parse("TRAP 10\nHALT").unwrap())
+            .unwrap();
+        cpu.step().unwrap();
+        assert!(cpu.step_back());
+        assert_eq!(cpu.step(), Ok(StepOutcome::WaitingForInput));
+        assert_eq!(cpu.future_len(), 1);
+        assert_eq!(cpu.instruction_count(), 0);
+    }
+
+    #[test]
+    fn unknown_trap_faults() {
+        let res = run("LDC 1\nTRAP 99\nHALT");
+        assert_eq!(res.unwrap_err().kind, FaultKind::UnknownTrap(99));
+    }
+
+    #[test]
+    fn bad_jump_faults() {
+        let res = run("LDC -5\nJSR");
+        assert_eq!(res.unwrap_err().kind, FaultKind::PcOutOfBounds);
+        let res = run("LDC 99\nLDC 100\nSTA 0\nLDC 100\nJSR");
+        assert_eq!(res.unwrap_err().kind, FaultKind::InvalidOpcode(99));
+    }
+
+    #[test]
+    fn negative_address_faults() {
+        let res = run("LDC -3\nLDA 0\nHALT");
+        assert_eq!(res.unwrap_err().kind, FaultKind::BadAddress(-3));
+    }
+
+    #[test]
+    fn heap_program() {
+        assert_eq!(
+            output(include_str!("../../tests/heap.ssm")),
+            include_str!("../../tests/heap.out")
+        );
+    }
+
+    #[test]
+    fn trace_is_kept_out_of_program_output() {
+        let io = BufferIo::new();
+        let mut cpu = Cpu::new(2, Box::new(io.clone()));
+        cpu.load_code(crate::parse().parse("LDC 42\nTRAP 0\nHALT").unwrap())
+            .unwrap();
+        while cpu.step() == Ok(StepOutcome::Running) {}
+        assert_eq!(io.output(), "42\n");
+        assert!(io.logs().iter().any(|l| l.starts_with("Executing")));
+    }
+
+    #[test]
+    fn input_program() {
+        assert_eq!(
+            output_with_input(
+                include_str!("../../tests/input.ssm"),
+                include_str!("../../tests/input.in")
+            ),
+            include_str!("../../tests/input.out")
+        );
+    }
+
+    #[test]
+    fn data_program() {
+        assert_eq!(
+            output(include_str!("../../tests/data.ssm")),
+            include_str!("../../tests/data.out")
+        );
+        let mut cpu = Cpu::new(0, Box::new(BufferIo::new()));
+        assert!(matches!(
+            cpu.load_code(crate::parse().parse(".space 2147483647").unwrap()),
+            Err(LoadError::CodeTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn java_encoding() {
+        let config = CpuConfig {
+            encoding: Encoding::Java,
+            ..CpuConfig::default()
+        };
+        let io = BufferIo::new();
+        let mut cpu = Cpu::with_config(config, 0, Box::new(io.clone())).unwrap();
+        let src = include_str!("../../tests/bigboy.ssm");
+        cpu.load_code(crate::parse().parse(src).unwrap()).unwrap();
+        assert_eq!(cpu.memory[..2], [0xa0, 1]);
+        while cpu.step() == Ok(StepOutcome::Running) {}
+        assert_eq!(io.output(), output(src));
+    }
+
+    #[test]
+    fn waits_for_input() {
+        let io = BufferIo::new();
+        let mut cpu = Cpu::new(0, Box::new(io.clone()));
+        cpu.load_code(crate::parse().parse("TRAP 10\nTRAP 0\nHALT").unwrap())
+            .unwrap();
+        assert_eq!(cpu.step(), Ok(StepOutcome::WaitingForInput));
+        assert_eq!(cpu.read_registers().pc, 0);
+        io.push_input("5\n");
+        while cpu.step() == Ok(StepOutcome::Running) {}
+        assert_eq!(io.output(), "5\n");
+    }
+
+    #[test]
+    fn custom_layout() {
+        let config = CpuConfig {
+            stack_size: 20,
+            heap_start: 100,
+            heap_limit: 102,
+            initial_registers: [(Reg::R4, 7)].into(),
+            ..Default::default()
+        };
+        let mut cpu = Cpu::with_config(config.clone(), 0, Box::new(BufferIo::new())).unwrap();
+        cpu.load_code(crate::parse().parse("LDR HP\nHALT").unwrap())
+            .unwrap();
+        assert_eq!(cpu.read_registers().r4, 7);
+        assert_eq!(cpu.step(), Ok(StepOutcome::Running));
+        assert_eq!(cpu.read_memory()[4], 100);
+
+        let src = "LDC 1\nSTH\nLDC 2\nSTH\nLDC 3\nSTH\nHALT";
+        let mut cpu = Cpu::with_config(config.clone(), 0, Box::new(BufferIo::new())).unwrap();
+        cpu.load_code(crate::parse().parse(src).unwrap()).unwrap();
+        while cpu.step() == Ok(StepOutcome::Running) {}
+        assert_eq!(cpu.read_heap(), &[1, 2]);
+        assert_eq!(cpu.step().unwrap_err().kind, FaultKind::BadAddress(102));
+
+        let mut cpu = Cpu::with_config(config, 0, Box::new(BufferIo::new())).unwrap();
+        let code = crate::parse().parse("LDC 1\n".repeat(11)).unwrap();
+        assert!(matches!(
+            cpu.load_code(code),
+            Err(LoadError::CodeTooLarge { code_size: 22, .. })
+        ));
+    }
+
+    #[test]
+    fn stack_overflow_faults() {
+        let res = run("loop: LDC 1\nBRA loop");
+        assert_eq!(res.unwrap_err().kind, FaultKind::StackOverflow);
+
+        let config = CpuConfig {
+            stack_size: 10,
+            heap_start: 10,
+            ..Default::default()
+        };
+        let mut cpu = Cpu::with_config(config, 0, Box::new(BufferIo::new())).unwrap();
+        cpu.load_code(crate::parse().parse("LDML 0 8\nHALT").unwrap())
+            .unwrap();
+        let fault = cpu.step().unwrap_err();
+        assert_eq!(fault.to_string(), "stack overflow at PC 0x0");
+        assert!(cpu.read_heap().is_empty());
+    }
+
+    #[test]
+    fn protected_code_faults() {
+        let src = "LDC 0\nLDC 5\nSTA 0\nHALT";
+        let config = CpuConfig {
+            protect_code: true,
+            ..Default::default()
+        };
+        let mut cpu = Cpu::with_config(config, 0, Box::new(BufferIo::new())).unwrap();
+        cpu.load_code(crate::parse().parse(src).unwrap()).unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.step(),
+            Err(CpuFault {
+                pc: 4,
+                kind: FaultKind::ReadOnlyCode(5)
+            })
+        );
+        assert_eq!(run(src), Ok(StepOutcome::Halted));
+    }
+
+    #[test]
+    fn segments() {
+        let config = CpuConfig {
+            stack_size: 20,
+            heap_start: 100,
+            heap_limit: 102,
+            ..Default::default()
+        };
+        let mut cpu = Cpu::with_config(config, 0, Box::new(BufferIo::new())).unwrap();
+        cpu.load_code(crate::parse().parse("LDC 50\nLDA 0\nHALT").unwrap())
+            .unwrap();
+        assert_eq!(cpu.segment(-1), None);
+        assert_eq!(cpu.segment(0), Some(Segment::Code));
+        assert_eq!(cpu.segment(5), Some(Segment::Stack));
+        assert_eq!(cpu.segment(50), None);
+        assert_eq!(cpu.segment(101), Some(Segment::Heap));
+        assert_eq!(cpu.segment(102), None);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step().unwrap_err().kind, FaultKind::BadAddress(50));
+    }
+
+    fn load(src: &str) -> Cpu {
+        let mut cpu = Cpu::new(0, Box::new(BufferIo::new()));
+        cpu.load_code(crate::parse().parse(src).unwrap()).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut cpu = load("LDC 1\nloop: LDC 2\nADD\nBRA loop");
+        assert_eq!(cpu.add_label_breakpoint("loop"), Some(2));
+        assert_eq!(cpu.add_label_breakpoint("missing"), None);
+        assert_eq!(
+            cpu.run_until(RunLimits::default()),
+            StopReason::Breakpoint(2)
+        );
+        assert_eq!(
+            cpu.run_until(RunLimits::default()),
+            StopReason::Breakpoint(2)
+        );
+        assert_eq!(cpu.read_memory()[8], 3);
+        assert!(matches!(
+            cpu.run_until(RunLimits::steps(2)),
+            StopReason::StepLimit { steps: 2, .. }
+        ));
+        assert!(cpu.remove_breakpoint(2));
+        cpu.add_breakpoint(5);
+        assert_eq!(
+            cpu.run_until(RunLimits::default()),
+            StopReason::Breakpoint(5)
+        );
+        assert_eq!(cpu.read_registers().pc, 5);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let src = "LDC 4\nLDC 4\nSTL 0\nLDL 0\nLDR R4\nSTR R4\nHALT";
+        let mut cpu = load(src);
+        let addr = WatchTarget::Memory(0);
+        cpu.add_watchpoint(Watchpoint::new(addr, WatchKind::Change));
+        cpu.add_watchpoint(Watchpoint::new(addr, WatchKind::Read));
+        cpu.add_watchpoint(Watchpoint::new(
+            WatchTarget::Register(Reg::R4),
+            WatchKind::Write,
+        ));
+        // MP is 0 and address 0 holds LDC, so STL 0 overwrites it with a different value.
+        let stop = cpu.run_until(RunLimits::default());
+        assert_eq!(
+            stop,
+            StopReason::Watchpoint {
+                watchpoint: Watchpoint::new(addr, WatchKind::Change),
+                pc: 4
             }
-            _ => (),
+        );
+        assert_eq!(stop.to_string(), "change of address 0 at PC 0x4");
+        assert!(matches!(
+            cpu.run_until(RunLimits::default()),
+            StopReason::Watchpoint { pc: 6, watchpoint } if watchpoint.kind == WatchKind::Read
+        ));
+        assert!(matches!(
+            cpu.run_until(RunLimits::default()),
+            StopReason::Watchpoint { pc: 10, .. }
+        ));
+        assert_eq!(cpu.run_until(RunLimits::default()), StopReason::Halted);
+    }
+
+    #[test]
+    fn step_back() {
+        let src = "LDC 5\nSTH\nLDC 7\nADD\nloop: LDC 1\nSTS -1\nBRA loop";
+        let mut cpu = load(src);
+        let mut states = vec![(*cpu.read_registers(), cpu.read_memory().to_vec())];
+        for _ in 0..8 {
+            cpu.step().unwrap();
+            states.push((*cpu.read_registers(), cpu.read_memory().to_vec()));
         }
+        assert_eq!(cpu.read_heap(), &[5]);
+        assert_eq!(cpu.history_len(), 8);
+        for (registers, memory) in states.iter().rev().skip(1) {
+            assert!(cpu.step_back());
+            assert_eq!(cpu.read_registers(), registers);
+            assert_eq!(cpu.read_memory(), memory);
+        }
+        assert!(!cpu.step_back());
+        assert!(cpu.read_heap().is_empty());
+        assert_eq!(cpu.future_len(), 8);
+
+        assert!(cpu.step_forward());
+        assert!(cpu.step_forward());
+        assert_eq!(cpu.read_heap(), &[5]);
+        assert_eq!(cpu.read_registers(), &states[2].0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.future_len(), 0);
+
+        cpu.add_label_breakpoint("loop");
+        assert_eq!(
+            cpu.run_until(RunLimits::steps(10)),
+            StopReason::Breakpoint(6)
+        );
+        assert!(matches!(
+            cpu.run_until(RunLimits::steps(3)),
+            StopReason::StepLimit { steps: 3, .. }
+        ));
+        assert_eq!(
+            cpu.run_back_until(RunLimits::default()),
+            StopReason::Breakpoint(6)
+        );
+        assert_eq!(
+            cpu.run_back_until(RunLimits::default()),
+            StopReason::StartOfHistory
+        );
+        assert_eq!(cpu.read_registers(), &states[0].0);
     }
-    code.retain(|instr| !matches!(instr, Instr::LABEL(_)));
-}
 
-fn remove_annote(code: &mut Code) {
-    code.retain(|instr| !matches!(instr, Instr::ANNOTE(_, _, _, _, _)));
-}
+    #[test]
+    fn history_limit() {
+        let config = CpuConfig {
+            history_limit: 2,
+            ..Default::default()
+        };
+        let mut cpu = Cpu::with_config(config, 0, Box::new(BufferIo::new())).unwrap();
+        cpu.load_code(crate::parse().parse("LDC 1\nLDC 2\nLDC 3\nHALT").unwrap())
+            .unwrap();
+        assert_eq!(cpu.run_until(RunLimits::default()), StopReason::Halted);
+        assert_eq!(
+            cpu.run_back_until(RunLimits::default()),
+            StopReason::StartOfHistory
+        );
+        assert_eq!(cpu.read_registers().pc, 4);
+    }
+
+    #[test]
+    fn run_limits() {
+        let mut cpu = load("LDC 1\nloop: BRA loop");
+        let reason = cpu.run_until(RunLimits::steps(1000));
+        assert_eq!(
+            reason.to_string(),
+            "step limit exceeded after 1000 instructions at PC 0x2"
+        );
+        assert_eq!(cpu.instruction_count(), 1000);
+        assert_eq!(cpu.read_registers().pc, 2);
+        cpu.step_back();
+        assert_eq!(cpu.instruction_count(), 999);
 
-fn convert_code(code: &Code) -> Vec<i32> {
-    code.iter().flat_map(Instr::convert).collect()
+        let limits = RunLimits {
+            max_steps: None,
+            timeout: Some(Duration::from_millis(10)),
+        };
+        assert!(matches!(
+            cpu.run_until(limits),
+            StopReason::Timeout { pc: 2, .. }
+        ));
+    }
 }
@@ -0,0 +1,203 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use crate::{cpu::StepOutcome, fault::CpuFault, register::Reg};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum WatchTarget {
+    Memory(i32),
+    Register(Reg),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// A write that stores a different value than the one already there.
+    Change,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Watchpoint {
+    pub target: WatchTarget,
+    pub kind: WatchKind,
+}
+
+/// A memory or register access made by the instruction being executed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Access {
+    pub target: WatchTarget,
+    pub write: bool,
+    pub changed: bool,
+}
+
+impl Watchpoint {
+    pub fn new(target: WatchTarget, kind: WatchKind) -> Self {
+        Self { target, kind }
+    }
+
+    pub(crate) fn matches(&self, access: &Access) -> bool {
+        self.target == access.target
+            && match self.kind {
+                WatchKind::Read => !access.write,
+                WatchKind::Write => access.write,
+                WatchKind::Change => access.changed,
+            }
+    }
+}
+
+/// Limits for [`Cpu::run_until`](crate::Cpu::run_until) and
+/// [`Cpu::run_back_until`](crate::Cpu::run_back_until). The default has no limits.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct RunLimits {
+    pub max_steps: Option<u64>,
+    /// Checked every [`TIMEOUT_CHECK_INTERVAL`] steps. Not supported on the web, which has no
+    /// clock to check it with.
+    pub timeout: Option<Duration>,
+}
+
+pub const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+impl RunLimits {
+    pub fn steps(max_steps: u64) -> Self {
+        Self {
+            max_steps: Some(max_steps),
+            timeout: None,
+        }
+    }
+}
+
+/// Keeps track of how much of a [`RunLimits`] has been used up.
+pub(crate) struct Budget {
+    limits: RunLimits,
+    steps: u64,
+    start: Option<std::time::Instant>,
+}
+
+impl Budget {
+    pub fn new(limits: RunLimits) -> Self {
+        Self {
+            limits,
+            steps: 0,
+            start: limits.timeout.map(|_| std::time::Instant::now()),
+        }
+    }
+
+    /// Returns why to stop before taking another step at `pc`, if the budget is used up.
+    pub fn exhausted(&self, pc: i32) -> Option<StopReason> {
+        let steps = self.steps;
+        if self.limits.max_steps.is_some_and(|max| steps >= max) {
+            return Some(StopReason::StepLimit { steps, pc });
+        }
+        if let (Some(start), Some(timeout)) = (self.start, self.limits.timeout) {
+            if steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && start.elapsed() >= timeout {
+                return Some(StopReason::Timeout { steps, pc });
+            }
+        }
+        None
+    }
+
+    pub fn spend(&mut self) {
+        self.steps += 1;
+    }
+}
+
+/// Why [`Cpu::run_until`](crate::Cpu::run_until) returned.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StopReason {
+    Halted,
+    WaitingForInput,
+    /// The PC reached a breakpoint. The instruction there has not been executed yet.
+    Breakpoint(i32),
+    /// The instruction at `pc` accessed a watched location.
+    Watchpoint {
+        watchpoint: Watchpoint,
+        pc: i32,
+    },
+    /// The step limit was reached after `steps` steps, before the instruction at `pc`.
+    StepLimit {
+        steps: u64,
+        pc: i32,
+    },
+    /// The time limit was reached after `steps` steps, before the instruction at `pc`.
+    Timeout {
+        steps: u64,
+        pc: i32,
+    },
+    Fault(CpuFault),
+    /// [`Cpu::run_back_until`](crate::Cpu::run_back_until) ran out of recorded steps.
+    StartOfHistory,
+}
+
+impl StopReason {
+    /// The reason to stop after a single step that left the PC at `pc`, where a step that keeps
+    /// the machine running counts as reaching a step limit of one.
+    pub fn from_step(res: Result<StepOutcome, CpuFault>, pc: i32) -> Self {
+        match res {
+            Ok(StepOutcome::Running) => StopReason::StepLimit { steps: 1, pc },
+            Ok(StepOutcome::Halted) => StopReason::Halted,
+            Ok(StepOutcome::WaitingForInput) => StopReason::WaitingForInput,
+            Err(fault) => StopReason::Fault(fault),
+        }
+    }
+}
+
+impl FromStr for WatchTarget {
+    type Err = String;
+
+    /// Parses a register name or a memory address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(reg) = s.parse() {
+            Ok(WatchTarget::Register(reg))
+        } else {
+            s.parse()
+                .map(WatchTarget::Memory)
+                .map_err(|_| format!("expected a register or an address, found {}", s))
+        }
+    }
+}
+
+impl Display for WatchTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchTarget::Memory(addr) => write!(f, "address {}", addr),
+            WatchTarget::Register(reg) => write!(f, "{}", reg),
+        }
+    }
+}
+
+impl Display for WatchKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchKind::Read => write!(f, "read of"),
+            WatchKind::Write => write!(f, "write to"),
+            WatchKind::Change => write!(f, "change of"),
+        }
+    }
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::Halted => write!(f, "machine halted"),
+            StopReason::WaitingForInput => write!(f, "waiting for input"),
+            StopReason::Breakpoint(pc) => write!(f, "breakpoint at PC 0x{:x}", pc),
+            StopReason::Watchpoint { watchpoint, pc } => write!(
+                f,
+                "{} {} at PC 0x{:x}",
+                watchpoint.kind, watchpoint.target, pc
+            ),
+            StopReason::StepLimit { steps, pc } => write!(
+                f,
+                "step limit exceeded after {} instructions at PC 0x{:x}",
+                steps, pc
+            ),
+            StopReason::Timeout { steps, pc } => write!(
+                f,
+                "time limit exceeded after {} instructions at PC 0x{:x}",
+                steps, pc
+            ),
+            StopReason::Fault(fault) => write!(f, "{}", fault),
+            StopReason::StartOfHistory => write!(f, "start of history"),
+        }
+    }
+}
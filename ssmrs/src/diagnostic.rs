@@ -0,0 +1,202 @@
+use std::{fmt::Display, ops::Range};
+
+use chumsky::{error::SimpleReason, prelude::Simple};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a source file. The span counts characters, not bytes.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Range<usize>,
+}
+
+impl Diagnostic {
+    pub fn error(span: Range<usize>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            span,
+        }
+    }
+
+    pub fn warning(span: Range<usize>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message: message.into(),
+            span,
+        }
+    }
+
+    pub fn from_parse_error(error: &Simple<char>, source: &str) -> Self {
+        let span = error.span();
+        let message = match error.reason() {
+            SimpleReason::Custom(message) => message.clone(),
+            SimpleReason::Unclosed { delimiter, .. } => format!("unclosed {:?}", delimiter),
+            SimpleReason::Unexpected => {
+                let found = match error.found() {
+                    Some('\n') => "end of line".to_string(),
+                    Some(c) => format!("{:?}", c),
+                    None if span.start >= source.chars().count() => "end of input".to_string(),
+                    None => format!("`{}`", slice(source, span.clone())),
+                };
+                let mut expected = error
+                    .expected()
+                    .map(|e| match e {
+                        Some(c) => format!("{:?}", c),
+                        None => "end of input".to_string(),
+                    })
+                    .collect::<Vec<_>>();
+                expected.sort();
+                match (error.label(), expected.as_slice()) {
+                    (Some(label), _) => format!("unexpected {}, expected {}", found, label),
+                    (None, []) => format!("unexpected {}", found),
+                    (None, [one]) => format!("unexpected {}, expected {}", found, one),
+                    (None, many) => {
+                        format!("unexpected {}, expected one of {}", found, many.join(", "))
+                    }
+                }
+            }
+        };
+        Self::error(span, message)
+    }
+
+    /// Formats the diagnostic with its location and the offending source line, marking the span
+    /// with carets.
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let (line, col) = line_col(source, self.span.start);
+        let text = source.lines().nth(line - 1).unwrap_or_default();
+        let width = line.to_string().len();
+        let carets = (self.span.end.saturating_sub(self.span.start))
+            .min(text.chars().count().saturating_sub(col - 1))
+            .max(1);
+        format!(
+            "{}: {}\n{:w$}--> {}:{}:{}\n{:w$} |\n{} | {}\n{:w$} | {}{}",
+            self.severity,
+            self.message,
+            "",
+            file,
+            line,
+            col,
+            "",
+            line,
+            text,
+            "",
+            " ".repeat(col - 1),
+            "^".repeat(carets),
+            w = width
+        )
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// The files of a program. Their spans share one range of character offsets, so a span also
+/// tells which file it is in.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Sources {
+    files: Vec<SourceFile>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SourceFile {
+    pub name: String,
+    pub text: String,
+    /// Offset of the first character of the file.
+    pub start: usize,
+}
+
+impl Sources {
+    /// Adds a file and returns the offset of its first character.
+    pub fn add(&mut self, name: &str, text: &str) -> usize {
+        let start = self
+            .files
+            .last()
+            .map_or(0, |f| f.start + f.text.chars().count() + 1);
+        self.files.push(SourceFile {
+            name: name.to_string(),
+            text: text.to_string(),
+            start,
+        });
+        start
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    /// The file an offset is in.
+    pub fn file(&self, offset: usize) -> Option<&SourceFile> {
+        self.files.iter().rev().find(|f| f.start <= offset)
+    }
+
+    /// The file, line and column of an offset.
+    pub fn locate(&self, offset: usize) -> Option<(&SourceFile, usize, usize)> {
+        let file = self.file(offset)?;
+        let (line, col) = line_col(&file.text, offset - file.start);
+        Some((file, line, col))
+    }
+
+    /// Renders a diagnostic in the file its span is in, like [`Diagnostic::render`].
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        match self.file(diagnostic.span.start) {
+            Some(file) => Diagnostic {
+                span: diagnostic.span.start - file.start..diagnostic.span.end - file.start,
+                ..diagnostic.clone()
+            }
+            .render(&file.name, &file.text),
+            None => format!("{}: {}", diagnostic.severity, diagnostic.message),
+        }
+    }
+}
+
+/// One-based line and column of a character offset.
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let mut line = 1;
+    let mut col = 1;
+    for c in source.chars().take(offset) {
+        if c == '\n' {
+            line += 1;
+            col = 1;
+        } else {
+            col += 1;
+        }
+    }
+    (line, col)
+}
+
+fn slice(source: &str, span: Range<usize>) -> String {
+    source
+        .chars()
+        .skip(span.start)
+        .take(span.end - span.start)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{line_col, Diagnostic};
+
+    #[test]
+    fn render() {
+        let source = "LDC 1\n  LDC x\n";
+        assert_eq!(line_col(source, 8), (2, 3));
+        let diagnostic = Diagnostic::error(12..13, "unexpected 'x', expected number");
+        assert_eq!(
+            diagnostic.render("test.ssm", source),
+            "error: unexpected 'x', expected number\n --> test.ssm:2:7\n  |\n2 |   LDC x\n  |       ^"
+        );
+    }
+}
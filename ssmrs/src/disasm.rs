@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    instruction::{
+        Encoding, Expr, Instr, Operand, OperandKind, SourceInstr, Unresolved, UnresolvedInstr,
+    },
+    Code,
+};
+
+/// The number of words on one `.word` line.
+const WORDS_PER_LINE: usize = 8;
+
+/// Turns encoded code back into assembly, which assembles to the same words.
+///
+/// Instructions are found by following the control flow from address 0, so code that is only
+/// reached through `JSR` or a computed address is shown as data, with `.word`. Branch targets get
+/// a label from `labels` when one points there, and a label like `L12` otherwise.
+pub fn disassemble(words: &[i32], labels: &BTreeMap<String, i32>, encoding: Encoding) -> Code {
+    let instrs = find_instrs(words, encoding);
+
+    let mut names: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for (name, &addr) in labels {
+        if let Ok(addr) = usize::try_from(addr) {
+            names.entry(addr).or_default().push(name.clone());
+        }
+    }
+    for (&addr, instr) in &instrs {
+        if let Some(target) = branch_target(addr, instr) {
+            names.entry(target).or_insert_with(|| {
+                let mut name = format!("L{}", target);
+                while labels.contains_key(&name) {
+                    name.push('_');
+                }
+                vec![name]
+            });
+        }
+    }
+    // Labels inside an instruction or past the end cannot be written.
+    names.retain(|&addr, _| {
+        let inside = instrs
+            .range(..addr)
+            .next_back()
+            .is_some_and(|(start, instr)| start + instr.instr_size() > addr);
+        addr <= words.len() && !inside
+    });
+
+    let mut code = Code::new();
+    let mut data = Vec::new();
+    let mut addr = 0;
+    while addr <= words.len() {
+        let instr = instrs.get(&addr);
+        if instr.is_some() || names.contains_key(&addr) || addr == words.len() {
+            code.extend(
+                data.chunks(WORDS_PER_LINE)
+                    .map(|w| SourceInstr::Word(w.to_vec())),
+            );
+            data.clear();
+        }
+        for name in names.get(&addr).into_iter().flatten() {
+            code.push(SourceInstr::Label(name.clone()));
+        }
+        match instr {
+            Some(instr) => {
+                code.push(with_label(addr, instr, &names));
+                addr += instr.instr_size();
+            }
+            None if addr < words.len() => {
+                data.push(words[addr]);
+                addr += 1;
+            }
+            None => break,
+        }
+    }
+    code
+}
+
+/// Decodes every instruction that can run when execution starts at address 0, by address.
+fn find_instrs(words: &[i32], encoding: Encoding) -> BTreeMap<usize, Instr> {
+    let mut instrs = BTreeMap::new();
+    // The addresses taken by the instructions found so far.
+    let mut taken = BTreeSet::new();
+    let mut todo = vec![0];
+    while let Some(addr) = todo.pop() {
+        if addr >= words.len() || taken.contains(&addr) {
+            continue;
+        }
+        let Ok(instr) = Instr::decode(&words[addr..], encoding) else {
+            continue;
+        };
+        let end = addr + instr.instr_size();
+        if (addr..end).any(|a| taken.contains(&a)) {
+            continue;
+        }
+        taken.extend(addr..end);
+        todo.extend(branch_target(addr, &instr));
+        if !matches!(instr, Instr::BRA(_) | Instr::RET | Instr::HALT) {
+            todo.push(end);
+        }
+        instrs.insert(addr, instr);
+    }
+    instrs
+}
+
+/// The address a branch at `addr` jumps to.
+fn branch_target(addr: usize, instr: &Instr) -> Option<usize> {
+    let kinds = instr.info().operands;
+    let i = kinds.iter().position(|&kind| kind == OperandKind::Offset)?;
+    let offset = instr.operands()[i] as i64;
+    usize::try_from((addr + instr.instr_size()) as i64 + offset).ok()
+}
+
+/// Writes a branch to a label when its target has one.
+fn with_label(addr: usize, instr: &Instr, names: &BTreeMap<usize, Vec<String>>) -> SourceInstr {
+    let name = branch_target(addr, instr).and_then(|target| names.get(&target)?.first());
+    let Some(name) = name else {
+        return instr.clone().into();
+    };
+    let mut unresolved = UnresolvedInstr::from(instr.clone());
+    // The offset is the only numeric operand of a branch.
+    for operand in unresolved.operands_mut() {
+        *operand = Operand::Expr(Expr::Label(name.clone()));
+    }
+    SourceInstr::Unresolved(Unresolved::Instr(unresolved))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::disassemble;
+    use crate::{
+        assembler::{assemble, resolve_labels},
+        instruction::{Encoding, Instr},
+        parser::print,
+        SourceInstr,
+    };
+
+    fn words(source: &str) -> Vec<i32> {
+        let mut code = assemble("test.ssm", source).unwrap().code;
+        resolve_labels(&mut code).unwrap();
+        code.iter().flat_map(|i| i.convert().unwrap()).collect()
+    }
+
+    #[test]
+    fn labels_branch_targets() {
+        let words = words("LDC 3\nloop: LDC 1\nSUB\nLDS 0\nBRT loop\nBSR done\ndone: HALT");
+        let labels = BTreeMap::from([("done".to_string(), 11)]);
+        assert_eq!(
+            print(&disassemble(&words, &labels, Encoding::Ssmrs)),
+            "    LDC 3\nL2:\n    LDC 1\n    SUB\n    LDS 0\n    BRT L2\n    BSR done\ndone:\n    HALT\n"
+        );
+    }
+
+    #[test]
+    fn marks_data() {
+        let words = words("LDC msg\nHALT\nmsg: .stringz \"hi\"\n.word 0x35, 1, 2");
+        let labels = BTreeMap::from([("msg".to_string(), 3)]);
+        assert_eq!(
+            disassemble(&words, &labels, Encoding::Ssmrs),
+            vec![
+                Instr::LDC(3).into(),
+                Instr::HALT.into(),
+                SourceInstr::Label("msg".to_string()),
+                SourceInstr::Word(vec![104, 105, 0, 0x35, 1, 2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_test_programs() {
+        let programs = [
+            include_str!("../../tests/bigboy.ssm"),
+            include_str!("../../tests/data.ssm"),
+            include_str!("../../tests/loop.ssm"),
+            include_str!("../../tests/cursed.ssm"),
+        ];
+        for src in programs {
+            let words = words(src);
+            let text = print(&disassemble(&words, &BTreeMap::new(), Encoding::Ssmrs));
+            assert_eq!(self::words(&text), words, "{}", text);
+        }
+    }
+
+    #[test]
+    fn decodes_java_opcodes() {
+        // LDC 1, BRT -4 and HALT with the opcodes of the Java SSM.
+        let words = vec![0x84, 1, 0x6d, -4, 0x74];
+        assert_eq!(
+            print(&disassemble(&words, &BTreeMap::new(), Encoding::Java)),
+            "L0:\n    LDC 1\n    BRT L0\n    HALT\n"
+        );
+    }
+
+    #[test]
+    fn keeps_offsets_without_labels() {
+        // `BRT` jumps into the operand of `BRA`, which jumps before the start.
+        let words = vec![0x0e, 1, 0x0c, -5, 0x0c, 10];
+        let labels = BTreeMap::from([("mid".to_string(), 3)]);
+        assert_eq!(
+            print(&disassemble(&words, &labels, Encoding::Ssmrs)),
+            "    BRT 1\n    BRA -5\n    .word 12, 10\n"
+        );
+    }
+}
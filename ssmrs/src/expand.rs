@@ -0,0 +1,321 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    ops::Range,
+};
+
+use crate::{
+    diagnostic::Diagnostic,
+    instruction::{AsmItem, Expr, Operand, SourceInstr, Unresolved},
+};
+
+#[derive(Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<AsmItem>,
+}
+
+#[derive(Default)]
+struct Expander {
+    constants: HashMap<String, Expr>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    /// The macros being expanded, outermost first.
+    active: Vec<String>,
+    /// The labels and constants in the source and the local labels made so far.
+    names: HashSet<String>,
+    code: Vec<(SourceInstr, Range<usize>)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Replaces constants by their values and macro uses by their bodies. Labels defined in a macro
+/// get a new name like `loop_1` for every use, which is not used anywhere else, and the code of
+/// a macro gets the span of the outermost use.
+pub(crate) fn expand(
+    items: Vec<(AsmItem, Range<usize>)>,
+) -> (Vec<(SourceInstr, Range<usize>)>, Vec<Diagnostic>) {
+    let mut expander = Expander::default();
+    for (item, _) in &items {
+        match item {
+            AsmItem::Instr(SourceInstr::Label(name)) | AsmItem::Equ(name, _) => {
+                expander.names.insert(name.clone());
+            }
+            _ => {}
+        }
+    }
+    let mut items = items.into_iter();
+    while let Some((item, span)) = items.next() {
+        match item {
+            AsmItem::Macro(name, params) => {
+                let mut body = Vec::new();
+                let mut closed = false;
+                for (item, item_span) in items.by_ref() {
+                    match item {
+                        AsmItem::EndMacro => {
+                            closed = true;
+                            break;
+                        }
+                        AsmItem::Macro(_, _) => expander.error(
+                            item_span,
+                            "macros cannot be defined inside another macro".to_string(),
+                        ),
+                        item => body.push(item),
+                    }
+                }
+                if !closed {
+                    expander.error(span, format!("macro `{}` has no `.endm`", name));
+                    continue;
+                }
+                match expander.macros.entry(name) {
+                    Entry::Occupied(entry) => expander.diagnostics.push(Diagnostic::error(
+                        span,
+                        format!("macro `{}` is already defined", entry.key()),
+                    )),
+                    Entry::Vacant(entry) => {
+                        entry.insert(Macro { params, body });
+                    }
+                }
+            }
+            AsmItem::EndMacro => expander.error(span, "`.endm` without `.macro`".to_string()),
+            item => expander.item(item, &span, &HashMap::new()),
+        }
+    }
+    (expander.code, expander.diagnostics)
+}
+
+impl Expander {
+    /// Reports an error once, since the code of a macro gets the span of its outermost use.
+    fn error(&mut self, span: Range<usize>, message: String) {
+        let diagnostic = Diagnostic::error(span, message);
+        if !self.diagnostics.contains(&diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    /// Replaces macro parameters and local labels from `scope`, and then constants.
+    fn substitute(&self, expr: &Expr, scope: &HashMap<String, Expr>) -> Expr {
+        expr.substitute(&|name| {
+            scope
+                .get(name)
+                .or_else(|| self.constants.get(name))
+                .cloned()
+        })
+    }
+
+    fn item(&mut self, item: AsmItem, span: &Range<usize>, scope: &HashMap<String, Expr>) {
+        match item {
+            AsmItem::Instr(instr) => {
+                let instr = self.instr(instr, span, scope);
+                let error = match &instr {
+                    SourceInstr::Unresolved(Unresolved::Space(_)) => {
+                        Some("the size of `.space` must be a constant")
+                    }
+                    SourceInstr::Space(n) if *n < 0 => {
+                        Some("the size of `.space` cannot be negative")
+                    }
+                    _ => None,
+                };
+                match error {
+                    Some(message) => self.error(span.clone(), message.to_string()),
+                    None => self.code.push((instr, span.clone())),
+                }
+            }
+            AsmItem::Equ(name, value) => {
+                let value = self.substitute(&value, scope);
+                match self.constants.entry(name) {
+                    Entry::Occupied(entry) => self.diagnostics.push(Diagnostic::error(
+                        span.clone(),
+                        format!("constant `{}` is already defined", entry.key()),
+                    )),
+                    Entry::Vacant(entry) => {
+                        entry.insert(value);
+                    }
+                }
+            }
+            AsmItem::Call(name, args) => {
+                let Some(m) = self.macros.get(&name).cloned() else {
+                    self.error(
+                        span.clone(),
+                        format!("unknown instruction or macro `{}`", name),
+                    );
+                    return;
+                };
+                if args.len() != m.params.len() {
+                    self.error(
+                        span.clone(),
+                        format!(
+                            "macro `{}` takes {} argument{}, but {} {} given",
+                            name,
+                            m.params.len(),
+                            if m.params.len() == 1 { "" } else { "s" },
+                            args.len(),
+                            if args.len() == 1 { "was" } else { "were" }
+                        ),
+                    );
+                    return;
+                }
+                if self.active.contains(&name) {
+                    self.error(span.clone(), format!("macro `{}` uses itself", name));
+                    return;
+                }
+                self.expansions += 1;
+                let mut inner = m
+                    .params
+                    .iter()
+                    .cloned()
+                    .zip(args.iter().map(|arg| self.substitute(arg, scope)))
+                    .collect::<HashMap<_, _>>();
+                for item in &m.body {
+                    if let AsmItem::Instr(SourceInstr::Label(label)) = item {
+                        let mut local = format!("{}_{}", label, self.expansions);
+                        while self.names.contains(&local) {
+                            local.push('_');
+                        }
+                        self.names.insert(local.clone());
+                        inner.insert(label.clone(), Expr::Label(local));
+                    }
+                }
+                self.active.push(name);
+                for item in m.body {
+                    self.item(item, span, &inner);
+                }
+                self.active.pop();
+            }
+            AsmItem::Macro(_, _) | AsmItem::EndMacro => {
+                unreachable!("macro definitions are handled by `expand`")
+            }
+            AsmItem::Include(_) => unreachable!("includes are handled by the assembler"),
+        }
+    }
+
+    fn instr(
+        &mut self,
+        instr: SourceInstr,
+        span: &Range<usize>,
+        scope: &HashMap<String, Expr>,
+    ) -> SourceInstr {
+        match instr {
+            SourceInstr::Label(name) => {
+                let name = match scope.get(&name) {
+                    Some(Expr::Label(local)) => local.clone(),
+                    _ => name,
+                };
+                if self.constants.contains_key(&name) {
+                    self.error(
+                        span.clone(),
+                        format!("`{}` is already defined as a constant", name),
+                    );
+                }
+                SourceInstr::Label(name)
+            }
+            SourceInstr::Unresolved(mut unresolved) => {
+                for operand in unresolved.operands_mut() {
+                    if let Operand::Expr(expr) = operand {
+                        *operand = self.substitute(expr, scope).into();
+                    }
+                }
+                unresolved.into()
+            }
+            instr => instr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assemble,
+        diagnostic::line_col,
+        instruction::{Expr, Operand, Unresolved, UnresolvedInstr},
+        parser::print,
+        Instr, SourceInstr,
+    };
+
+    #[test]
+    fn constants_and_macros() {
+        let src = "\
+.equ SIZE 3
+.equ LAST SIZE-1
+.macro count from
+  LDC from
+loop: LDS 0
+  TRAP 0
+  LDC 1
+  SUB
+  LDS 0
+  BRT loop
+  AJS -1
+.endm
+count LAST
+  count 'a'-96
+  AJS -SIZE
+  HALT";
+        let assembly = assemble("test.ssm", src).unwrap();
+        assert!(assembly.warnings.is_empty());
+        let code = assembly.code;
+        assert_eq!(code[0], SourceInstr::Instr(Instr::LDC(2)));
+        assert_eq!(code[1], SourceInstr::Label("loop_1".to_string()));
+        assert_eq!(
+            code[7],
+            SourceInstr::Unresolved(Unresolved::Instr(UnresolvedInstr::BRT(Operand::Expr(
+                Expr::Label("loop_1".to_string())
+            ))))
+        );
+        assert_eq!(code[10], SourceInstr::Label("loop_2".to_string()));
+        assert_eq!(code[code.len() - 2], SourceInstr::Instr(Instr::AJS(-3)));
+        assert_eq!(assembly.source_map.get(2).unwrap().line, 13);
+
+        let io = crate::io::BufferIo::new();
+        let mut cpu = crate::Cpu::new(0, Box::new(io.clone()));
+        cpu.load_code(code).unwrap();
+        while cpu.step() == Ok(crate::cpu::StepOutcome::Running) {}
+        assert_eq!(io.output(), "2\n1\n1\n");
+    }
+
+    #[test]
+    fn local_labels_parse_again() {
+        let src = "loop_1: NOP\n.macro m\nloop: BRA loop\n.endm\nm\nm\nBRA loop_1\nHALT";
+        let code = assemble("test.ssm", src).unwrap().code;
+        assert_eq!(code[2], SourceInstr::Label("loop_1_".to_string()));
+        assert_eq!(code[4], SourceInstr::Label("loop_2".to_string()));
+        let printed = print(&code);
+        assert_eq!(assemble("printed.ssm", &printed).unwrap().code, code);
+    }
+
+    #[test]
+    fn macro_errors() {
+        let src = "\
+.macro twice x
+  twice x
+.endm
+.equ A 1
+.equ A 2
+A: twice 1 2
+twice 1
+frob 3
+.endm
+.macro m
+  m
+  m
+.endm
+m
+.macro open";
+        let errors = assemble("test.ssm", src).unwrap_err();
+        let messages = errors
+            .iter()
+            .map(|e| format!("{}: {}", line_col(src, e.span.start).0, e.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                "5: constant `A` is already defined",
+                "6: `A` is already defined as a constant",
+                "6: macro `twice` takes 1 argument, but 2 were given",
+                "7: macro `twice` uses itself",
+                "8: unknown instruction or macro `frob`",
+                "9: `.endm` without `.macro`",
+                "14: macro `m` uses itself",
+                "15: macro `open` has no `.endm`",
+            ]
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use std::fmt::Display;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultKind {
+    DivideByZero,
+    InvalidOpcode(i32),
+    InvalidRegister(i32),
+    UnknownTrap(i32),
+    PcOutOfBounds,
+    BadAddress(i32),
+    ReadOnlyCode(i32),
+    StackOverflow,
+}
+
+/// A runtime error raised by the program running on the [`Cpu`](crate::Cpu).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CpuFault {
+    pub pc: i32,
+    pub kind: FaultKind,
+}
+
+impl Display for FaultKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FaultKind::DivideByZero => write!(f, "division by zero"),
+            FaultKind::InvalidOpcode(op) => write!(f, "invalid opcode 0x{:x}", op),
+            FaultKind::InvalidRegister(r) => write!(f, "invalid register {}", r),
+            FaultKind::UnknownTrap(t) => write!(f, "unknown trap {}", t),
+            FaultKind::PcOutOfBounds => write!(f, "program counter out of bounds"),
+            FaultKind::BadAddress(addr) => write!(f, "bad memory address {}", addr),
+            FaultKind::ReadOnlyCode(addr) => write!(f, "write to read-only code at {}", addr),
+            FaultKind::StackOverflow => write!(f, "stack overflow"),
+        }
+    }
+}
+
+impl Display for CpuFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at PC 0x{:x}", self.kind, self.pc)
+    }
+}
+
+impl std::error::Error for CpuFault {}
@@ -0,0 +1,317 @@
+use crate::{
+    diagnostic::Diagnostic,
+    instruction::{AsmItem, SourceInstr, Unresolved},
+    parser::{is_mnemonic, parse_spanned},
+};
+
+const INDENT: &str = "    ";
+
+/// Formats assembly source in a standard layout.
+///
+/// Labels and the `.equ`, `.macro`, `.endm` and `.include` directives start at the beginning of
+/// the line, everything else is indented by four spaces. Every item gets its own line, mnemonics
+/// and directives are written in one case, operands are separated by single spaces and trailing
+/// comments on consecutive lines line up. Comments and blank lines are kept, operands are written
+/// as they were. Source that does not parse is not formatted.
+pub fn format_source(source: &str) -> Result<String, Vec<Diagnostic>> {
+    let items = parse_spanned(source)?;
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut covered = vec![false; chars.len()];
+    for (_, span) in &items {
+        covered[span.clone()].iter_mut().for_each(|c| *c = true);
+    }
+
+    let mut lines = Vec::new();
+    let mut items = items.iter().peekable();
+    let mut start = 0;
+    while start < chars.len() {
+        let end = chars[start..]
+            .iter()
+            .position(|&c| c == '\n')
+            .map_or(chars.len(), |i| start + i);
+        let mut code = Vec::new();
+        while let Some((item, span)) = items.next_if(|(_, span)| span.start < end) {
+            code.push(format_item(item, &chars[span.clone()]));
+        }
+        let comment = (start..end)
+            .find(|&i| !covered[i] && !chars[i].is_whitespace())
+            .map(|i| {
+                (
+                    i,
+                    chars[i..end]
+                        .iter()
+                        .collect::<String>()
+                        .trim_end()
+                        .to_string(),
+                )
+            });
+        match (code.pop(), comment) {
+            (None, None) => lines.push(Line::Blank),
+            (None, Some((i, text))) => lines.push(Line::Comment {
+                indented: i > start,
+                text,
+            }),
+            (Some(last), comment) => {
+                lines.extend(code.into_iter().map(|code| Line::Code {
+                    code,
+                    comment: None,
+                }));
+                lines.push(Line::Code {
+                    code: last,
+                    comment: comment.map(|(_, text)| text),
+                });
+            }
+        }
+        start = end + 1;
+    }
+    Ok(render(&lines))
+}
+
+enum Line {
+    Blank,
+    Comment {
+        indented: bool,
+        text: String,
+    },
+    Code {
+        code: String,
+        comment: Option<String>,
+    },
+}
+
+/// Writes the lines, dropping repeated blank lines and lining up runs of trailing comments.
+fn render(lines: &[Line]) -> String {
+    let mut out = String::new();
+    let mut column = None;
+    for (i, line) in lines.iter().enumerate() {
+        match line {
+            Line::Blank => {
+                if !out.is_empty() && !out.ends_with("\n\n") {
+                    out.push('\n');
+                }
+            }
+            Line::Comment { indented, text } => {
+                if *indented {
+                    out.push_str(INDENT);
+                }
+                out.push_str(text);
+                out.push('\n');
+            }
+            Line::Code { code, comment } => {
+                out.push_str(code);
+                if let Some(comment) = comment {
+                    let column = *column.get_or_insert_with(|| comment_column(&lines[i..]));
+                    out.push_str(&" ".repeat(column - code.chars().count()));
+                    out.push_str(comment);
+                }
+                out.push('\n');
+            }
+        }
+        if !matches!(
+            line,
+            Line::Code {
+                comment: Some(_),
+                ..
+            }
+        ) {
+            column = None;
+        }
+    }
+    let trimmed = out.trim_end_matches('\n').len();
+    out.truncate(trimmed);
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out
+}
+
+/// The column for the trailing comments of the run of commented code lines that starts here.
+fn comment_column(lines: &[Line]) -> usize {
+    lines
+        .iter()
+        .map_while(|line| match line {
+            Line::Code {
+                code,
+                comment: Some(_),
+            } => Some(code.chars().count() + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+fn format_item(item: &AsmItem, text: &[char]) -> String {
+    match item {
+        AsmItem::Instr(SourceInstr::Label(_)) => text.iter().collect(),
+        AsmItem::Equ(..) | AsmItem::Macro(..) | AsmItem::EndMacro | AsmItem::Include(_) => {
+            join(&words(text, false, usize::MAX).0)
+        }
+        // The text of an annotation is written as it was, since it may contain anything.
+        AsmItem::Instr(SourceInstr::Annote(..)) => {
+            let (words, rest) = words(text, false, 5);
+            format!("{}{} {}", INDENT, join(&words), rest)
+                .trim_end()
+                .to_string()
+        }
+        AsmItem::Instr(instr) => {
+            let data = matches!(
+                instr,
+                SourceInstr::Word(_) | SourceInstr::Unresolved(Unresolved::Word(_))
+            );
+            format!("{}{}", INDENT, join(&words(text, data, usize::MAX).0))
+        }
+        AsmItem::Call(..) => format!("{}{}", INDENT, join(&words(text, false, usize::MAX).0)),
+    }
+}
+
+/// Joins the words of an item, with the first one in the case the other sources use.
+fn join(words: &[(String, bool)]) -> String {
+    let mut out = String::new();
+    for (i, (word, comma)) in words.iter().enumerate() {
+        if i == 0 && word.starts_with('.') {
+            out.push_str(&word.to_ascii_lowercase());
+        } else if i == 0 && is_mnemonic(word) {
+            out.push_str(&word.to_ascii_uppercase());
+        } else {
+            out.push_str(word);
+        }
+        if i + 1 < words.len() {
+            out.push_str(if *comma { ", " } else { " " });
+        }
+    }
+    out
+}
+
+/// Splits off up to `limit` words of an item, each with whether a comma follows it, and returns
+/// them with the rest of the text. Quoted strings and characters stay in one word, and commas
+/// only separate words in `.word` lists.
+fn words(text: &[char], commas: bool, limit: usize) -> (Vec<(String, bool)>, String) {
+    let mut words: Vec<(String, bool)> = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    let mut i = 0;
+    while i < text.len() {
+        let c = text[i];
+        if let Some(q) = quote {
+            word.push(c);
+            if c == '\\' && i + 1 < text.len() {
+                i += 1;
+                word.push(text[i]);
+            } else if c == q {
+                quote = None;
+            }
+        } else if c.is_whitespace() || (commas && c == ',') {
+            if !word.is_empty() {
+                words.push((std::mem::take(&mut word), false));
+            }
+            if c == ',' {
+                if let Some(last) = words.last_mut() {
+                    last.1 = true;
+                }
+            }
+        } else if word.is_empty() && words.len() == limit {
+            break;
+        } else {
+            if c == '"' || c == '\'' {
+                quote = Some(c);
+            }
+            word.push(c);
+        }
+        i += 1;
+    }
+    if !word.is_empty() {
+        words.push((word, false));
+    }
+    (words, text[i..].iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::format_source;
+    use crate::parser::parse_source;
+
+    #[test]
+    fn formats_layout() {
+        let src = "\n\n; setup\nmain:\tldc  5 ; five\n  .WORD 1 ,2,3 // data\n\tbsr   main\n\n\n\
+                   .equ N 'x'\n   // end\nhalt\n\n";
+        let expected = "; setup\nmain:\n    LDC 5         ; five\n    .word 1, 2, 3 // data\n    \
+                        BSR main\n\n.equ N 'x'\n    // end\n    HALT\n";
+        assert_eq!(format_source(src), Ok(expected.to_string()));
+    }
+
+    /// Checks that formatting keeps the program the same and formats it completely.
+    fn check(src: &str) -> String {
+        let formatted = format_source(src).unwrap();
+        assert_eq!(parse_source(&formatted), parse_source(src), "{}", formatted);
+        assert_eq!(format_source(&formatted), Ok(formatted.clone()));
+        formatted
+    }
+
+    #[test]
+    fn keeps_quoted_text() {
+        let src = "msg: .string \"a ; b,  c\" ; text\nannote SP 0 0 red \"x  // y\"\nLDC ';'";
+        let expected = "msg:\n    .string \"a ; b,  c\" ; text\n    \
+                        ANNOTE SP 0 0 red \"x  // y\"\n    LDC ';'\n";
+        assert_eq!(check(src), expected);
+    }
+
+    #[test]
+    fn keeps_commas_outside_lists() {
+        let src = "ldc 1+','\nANNOTE SP 0 0 red a,b\n.word ',', 1+',' ,2\nLDC ' '+'\t'\n\
+                   annote MP ' ' 0 blue it's\ta,b ; note";
+        let expected = "    LDC 1+','\n    ANNOTE SP 0 0 red a,b\n    .word ',', 1+',', 2\n    \
+                        LDC ' '+'\t'\n    ANNOTE MP ' ' 0 blue it's\ta,b ; note\n";
+        assert_eq!(check(src), expected);
+    }
+
+    #[test]
+    fn rejects_bad_source() {
+        assert!(format_source("LDC\n").is_err());
+    }
+
+    #[test]
+    fn formats_test_programs() {
+        for entry in std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/../tests")).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "ssm") {
+                check(&std::fs::read_to_string(path).unwrap());
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn keeps_programs(
+            lines in prop::collection::vec((0..LINES.len(), "[ \t]{0,2}", "[ \t]{1,3}", any::<bool>()), 0..12)
+        ) {
+            let src = lines
+                .iter()
+                .map(|(i, indent, gap, upper)| {
+                    let (first, rest) = LINES[*i].split_once(' ').unwrap_or((LINES[*i], ""));
+                    let first = if *upper { first.to_string() } else { first.to_lowercase() };
+                    format!("{}{}{}{}", indent, first, gap, rest.replace(' ', gap))
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            check(&src);
+        }
+    }
+
+    /// Lines whose first word can be written in any case.
+    const LINES: &[&str] = &[
+        "",
+        "LOOP: LDC 1 ; ONE",
+        "BRA LOOP // BACK",
+        "LDMA 0 2",
+        "LDRR R7 MP",
+        ".WORD 1, 2 ,3",
+        ".STRING \"A, B ; C\"",
+        "LDC 'X'+','",
+        "ANNOTE SP 0 0 RED A,B",
+        "ANNOTE SP -1 1 GREEN \"X  Y\"",
+        "// NOTE",
+        "X: Y: NOP HALT",
+    ];
+}
@@ -1,307 +1,619 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
-use crate::register::Reg;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, PartialEq, Eq, Debug)]
-pub enum Instr {
-    STR(Reg),
-    STL(i32),
-    STS(i32),
-    STA(i32),
-    LDR(Reg),
-    LDL(i32),
-    LDS(i32),
-    LDA(i32),
-    LDC(i32),
-    LDLA(i32),
-    LDSA(i32),
-    LDAA(i32),
-    BRA(i32),
-    Bra(String),
-    BRF(i32),
-    Brf(String),
-    BRT(i32),
-    Brt(String),
-    BSR(i32),
-    Bsr(String),
-    ADD,
-    SUB,
-    MUL,
-    DIV,
-    MOD,
-    EQ,
-    NE,
-    LT,
-    LE,
-    GT,
-    GE,
-    AND,
-    OR,
-    XOR,
-    NEG,
-    NOT,
-    RET,
-    UNLINK,
-    LINK(i32),
-    AJS(i32),
-    SWP,
-    SWPR(Reg),
-    SWPRR(Reg, Reg),
-    LDRR(Reg, Reg),
-    JSR,
-    TRAP(i32),
-    NOP,
-    HALT,
-    STH,
-    STMA(i32, i32),
-    STMH(i32),
-    STML(i32, i32),
-    STMS(i32, i32),
-    LDH(i32),
-    LDMA(i32, i32),
-    LDMH(i32, i32),
-    LDML(i32, i32),
-    LDMS(i32, i32),
-    LABEL(String),
-    ANNOTE(Reg, i32, i32, Color, String),
+use crate::{fault::FaultKind, register::Reg};
+
+/// What an operand of an instruction is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OperandKind {
+    Number,
+    /// A number of words relative to the address after the instruction.
+    Offset,
+    Register,
 }
 
-impl Instr {
-    pub fn convert(&self) -> Vec<i32> {
+/// Everything about an instruction that does not depend on its operands.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InstrInfo {
+    pub mnemonic: &'static str,
+    pub opcode: i32,
+    /// The opcode in the original Java SSM.
+    pub java_opcode: i32,
+    pub operands: &'static [OperandKind],
+    /// How much the instruction moves the stack pointer, or `None` if that depends on the
+    /// operands or on the machine.
+    pub stack_effect: Option<i32>,
+    pub description: &'static str,
+}
+
+impl InstrInfo {
+    /// The number of words the instruction takes in memory.
+    pub const fn size(&self) -> usize {
+        1 + self.operands.len()
+    }
+
+    pub fn opcode_in(&self, encoding: Encoding) -> i32 {
+        match encoding {
+            Encoding::Ssmrs => self.opcode,
+            Encoding::Java => self.java_opcode,
+        }
+    }
+}
+
+/// The opcodes instructions are encoded with. Both put the operands in the words after the
+/// opcode, in the order they are written in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// The opcodes of this implementation, numbered from 0 in the order of [`INSTRUCTIONS`].
+    #[default]
+    Ssmrs,
+    /// The opcodes of the original Java SSM, so memory can be compared with it word for word.
+    Java,
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Instr::STR(n) => {
-                vec![0x00, *n as i32]
-            }
-            Instr::STL(n) => {
-                vec![0x01, *n]
-            }
-            Instr::STS(n) => {
-                vec![0x02, *n]
-            }
-            Instr::STA(n) => {
-                vec![0x03, *n]
-            }
-            Instr::LDR(n) => {
-                vec![0x04, *n as i32]
-            }
-            Instr::LDL(n) => {
-                vec![0x05, *n]
-            }
-            Instr::LDS(n) => {
-                vec![0x06, *n]
-            }
-            Instr::LDA(n) => {
-                vec![0x07, *n]
-            }
-            Instr::LDC(n) => {
-                vec![0x08, *n]
-            }
-            Instr::LDLA(n) => {
-                vec![0x09, *n]
-            }
-            Instr::LDSA(n) => {
-                vec![0x0A, *n]
-            }
-            Instr::LDAA(n) => {
-                vec![0x0B, *n]
-            }
-            Instr::BRA(n) => {
-                vec![0x0C, *n]
-            }
-            Instr::Bra(_) => {
-                panic!("Bra should never be executed!")
-            }
-            Instr::BRF(n) => {
-                vec![0x0D, *n]
-            }
-            Instr::Brf(_) => {
-                panic!("Brf should never be executed!")
-            }
-            Instr::BRT(n) => {
-                vec![0x0E, *n]
-            }
-            Instr::Brt(_) => {
-                panic!("Brt should never be executed!")
-            }
-            Instr::BSR(n) => {
-                vec![0x0F, *n]
-            }
-            Instr::Bsr(_) => {
-                panic!("Bsr should never be executed!")
-            }
-            Instr::ADD => {
-                vec![0x10]
-            }
-            Instr::SUB => {
-                vec![0x11]
-            }
-            Instr::MUL => {
-                vec![0x12]
-            }
-            Instr::DIV => {
-                vec![0x13]
-            }
-            Instr::MOD => {
-                vec![0x14]
-            }
-            Instr::EQ => {
-                vec![0x15]
-            }
-            Instr::NE => {
-                vec![0x16]
-            }
-            Instr::LT => {
-                vec![0x17]
-            }
-            Instr::LE => {
-                vec![0x18]
-            }
-            Instr::GT => {
-                vec![0x19]
-            }
-            Instr::GE => {
-                vec![0x1A]
-            }
-            Instr::NEG => {
-                vec![0x1B]
-            }
-            Instr::NOT => {
-                vec![0x1C]
-            }
-            Instr::RET => {
-                vec![0x1D]
-            }
-            Instr::UNLINK => {
-                vec![0x1E]
-            }
-            Instr::LINK(n) => {
-                vec![0x1F, *n]
-            }
-            Instr::AJS(n) => {
-                vec![0x20, *n]
-            }
-            Instr::SWP => {
-                vec![0x21]
-            }
-            Instr::SWPR(n) => {
-                vec![0x22, *n as i32]
-            }
-            Instr::SWPRR(n, m) => {
-                vec![0x23, *n as i32, *m as i32]
-            }
-            Instr::LDRR(n, m) => {
-                vec![0x24, *n as i32, *m as i32]
-            }
-            Instr::JSR => {
-                vec![0x25]
-            }
-            Instr::TRAP(n) => {
-                vec![0x26, *n]
-            }
-            Instr::NOP => {
-                vec![0x27]
-            }
-            Instr::HALT => {
-                vec![0x28]
-            }
-            Instr::AND => {
-                vec![0x29]
-            }
-            Instr::OR => {
-                vec![0x2A]
-            }
-            Instr::XOR => {
-                vec![0x2B]
-            }
-            Instr::STH => {
-                vec![0x2C]
+            Encoding::Ssmrs => write!(f, "ssmrs"),
+            Encoding::Java => write!(f, "java"),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ssmrs" => Ok(Encoding::Ssmrs),
+            "java" => Ok(Encoding::Java),
+            _ => Err(format!("unknown encoding `{}`, expected ssmrs or java", s)),
+        }
+    }
+}
+
+/// A type that an operand is stored in, taking one word in memory.
+trait OperandType: Sized + Display {
+    fn to_word(&self) -> i32;
+    fn from_word(word: i32) -> Result<Self, FaultKind>;
+}
+
+impl OperandType for i32 {
+    fn to_word(&self) -> i32 {
+        *self
+    }
+
+    fn from_word(word: i32) -> Result<Self, FaultKind> {
+        Ok(word)
+    }
+}
+
+impl OperandType for Reg {
+    fn to_word(&self) -> i32 {
+        *self as i32
+    }
+
+    fn from_word(word: i32) -> Result<Self, FaultKind> {
+        Reg::try_from(word).map_err(|_| FaultKind::InvalidRegister(word))
+    }
+}
+
+macro_rules! operand_type {
+    (Number) => {
+        i32
+    };
+    (Offset) => {
+        i32
+    };
+    (Register) => {
+        Reg
+    };
+}
+
+macro_rules! unresolved_type {
+    (Register) => {
+        Reg
+    };
+    ($kind:ident) => {
+        Operand
+    };
+}
+
+/// Converts an operand of [`Instr`] to or from the same operand of [`UnresolvedInstr`].
+macro_rules! operand {
+    (unresolve Register, $operand:expr) => {
+        $operand
+    };
+    (unresolve $kind:ident, $operand:expr) => {
+        Operand::Value($operand)
+    };
+    (resolve Register, $operand:expr, $eval:expr) => {
+        *$operand
+    };
+    (resolve $kind:ident, $operand:expr, $eval:expr) => {
+        $eval($operand)?
+    };
+    (numeric Register, $operand:expr) => {{
+        let _ = $operand;
+        None
+    }};
+    (numeric $kind:ident, $operand:expr) => {
+        Some($operand)
+    };
+}
+
+/// Defines [`Instr`], [`UnresolvedInstr`], [`INSTRUCTIONS`] and everything that follows from
+/// them, like encoding and decoding, from one line per instruction. A line gives the operands, the opcode, the opcode in
+/// the Java SSM, the stack effect and a description.
+macro_rules! instructions {
+    ($(
+        $name:ident $(($($operand:ident: $kind:ident),*))? = $opcode:literal, $java_opcode:literal,
+        $effect:expr, $description:literal;
+    )*) => {
+        #[derive(Clone, PartialEq, Eq, Debug)]
+        pub enum Instr {
+            $($name $(($(operand_type!($kind)),*))?,)*
+        }
+
+        /// Every instruction, in the order of their opcodes.
+        pub const INSTRUCTIONS: &[InstrInfo] = &[$(InstrInfo {
+            mnemonic: stringify!($name),
+            opcode: $opcode,
+            java_opcode: $java_opcode,
+            operands: &[$($(OperandKind::$kind),*)?],
+            stack_effect: $effect,
+            description: $description,
+        }),*];
+
+        /// An [`Instr`] whose numeric operands may refer to labels.
+        #[derive(Clone, PartialEq, Eq, Debug)]
+        pub enum UnresolvedInstr {
+            $($name $(($(unresolved_type!($kind)),*))?,)*
+        }
+
+        /// The position of each instruction in [`INSTRUCTIONS`].
+        #[allow(clippy::upper_case_acronyms)]
+        enum Index {
+            $($name,)*
+        }
+
+        impl Instr {
+            pub fn info(&self) -> &'static InstrInfo {
+                match self {
+                    $(Instr::$name { .. } => &INSTRUCTIONS[Index::$name as usize],)*
+                }
             }
-            Instr::STMH(n) => {
-                vec![0x2D, *n]
+
+            /// The operands as they are stored in memory.
+            pub fn operands(&self) -> Vec<i32> {
+                match self {
+                    $(Instr::$name $(($($operand),*))? => vec![$($($operand.to_word()),*)?],)*
+                }
             }
-            Instr::LDH(n) => {
-                vec![0x2E, *n]
+
+            pub fn name_and_params(&self) -> Vec<String> {
+                match self {
+                    $(Instr::$name $(($($operand),*))? => {
+                        vec![String::from(stringify!($name)) $($(, $operand.to_string())*)?]
+                    })*
+                }
             }
-            Instr::LDMH(n, m) => {
-                vec![0x2F, *n, *m]
+        }
+
+        impl Instr {
+            /// Decodes the instruction at the start of `words`.
+            pub fn decode(words: &[i32], encoding: Encoding) -> Result<Instr, FaultKind> {
+                let (&opcode, operands) = words.split_first().ok_or(FaultKind::PcOutOfBounds)?;
+                let mut operands = operands.iter().copied();
+                let mut next = || operands.next().ok_or(FaultKind::PcOutOfBounds);
+                let instr = match (encoding, opcode) {
+                    $((Encoding::Ssmrs, $opcode) | (Encoding::Java, $java_opcode) => Instr::$name $((
+                        $(<operand_type!($kind)>::from_word(next()?)?),*
+                    ))?,)*
+                    (_, code) => return Err(FaultKind::InvalidOpcode(code)),
+                };
+                Ok(instr)
             }
-            Instr::STMA(n, m) => {
-                vec![0x30, *n, *m]
+        }
+
+        impl UnresolvedInstr {
+            pub fn info(&self) -> &'static InstrInfo {
+                match self {
+                    $(UnresolvedInstr::$name { .. } => &INSTRUCTIONS[Index::$name as usize],)*
+                }
             }
-            Instr::LDMA(n, m) => {
-                vec![0x31, *n, *m]
+
+            /// The numeric operands, in order. Register operands are not included.
+            pub fn operands(&self) -> Vec<&Operand> {
+                let operands: Vec<Option<&Operand>> = match self {
+                    $(UnresolvedInstr::$name $(($($operand),*))? => {
+                        vec![$($(operand!(numeric $kind, $operand)),*)?]
+                    })*
+                };
+                operands.into_iter().flatten().collect()
             }
-            Instr::STML(n, m) => {
-                vec![0x32, *n, *m]
+
+            /// Like [`UnresolvedInstr::operands`], but mutable.
+            pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+                let operands: Vec<Option<&mut Operand>> = match self {
+                    $(UnresolvedInstr::$name $(($($operand),*))? => {
+                        vec![$($(operand!(numeric $kind, $operand)),*)?]
+                    })*
+                };
+                operands.into_iter().flatten().collect()
             }
-            Instr::STMS(n, m) => {
-                vec![0x33, *n, *m]
+
+            /// Makes the instruction with the values `eval` gives the numeric operands.
+            pub fn resolve<E>(
+                &self,
+                eval: &mut impl FnMut(&Operand) -> Result<i32, E>,
+            ) -> Result<Instr, E> {
+                Ok(match self {
+                    $(UnresolvedInstr::$name $(($($operand),*))? => Instr::$name $((
+                        $(operand!(resolve $kind, $operand, eval)),*
+                    ))?,)*
+                })
             }
-            Instr::LDML(n, m) => {
-                vec![0x34, *n, *m]
+
+            pub fn name_and_params(&self) -> Vec<String> {
+                match self {
+                    $(UnresolvedInstr::$name $(($($operand),*))? => {
+                        vec![String::from(stringify!($name)) $($(, $operand.to_string())*)?]
+                    })*
+                }
             }
-            Instr::LDMS(n, m) => {
-                vec![0x35, *n, *m]
+        }
+
+        impl From<Instr> for UnresolvedInstr {
+            fn from(instr: Instr) -> Self {
+                match instr {
+                    $(Instr::$name $(($($operand),*))? => UnresolvedInstr::$name $((
+                        $(operand!(unresolve $kind, $operand)),*
+                    ))?,)*
+                }
             }
-            Instr::LABEL(_) => {
-                panic!("LABEL should never be executed!")
+        }
+
+        impl TryFrom<&[i32]> for Instr {
+            type Error = FaultKind;
+
+            fn try_from(v: &[i32]) -> Result<Self, Self::Error> {
+                Instr::decode(v, Encoding::Ssmrs)
             }
-            Instr::ANNOTE(_, _, _, _, _) => {
-                panic!("ANNOTE should never be executed!")
+        }
+    };
+}
+
+instructions! {
+    STR(r: Register) = 0x00, 0xB4, Some(-1), "Pops a value into a register";
+    STL(n: Number) = 0x01, 0xB0, Some(-1), "Pops a value and stores it at MP + n";
+    STS(n: Number) = 0x02, 0xB8, Some(-1), "Pops a value and stores it at SP + n";
+    STA(n: Number) = 0x03, 0xAC, Some(-2), "Pops an address, then pops a value and stores it at the address + n";
+    LDR(r: Register) = 0x04, 0x90, Some(1), "Pushes the value of a register";
+    LDL(n: Number) = 0x05, 0x88, Some(1), "Pushes the value at MP + n";
+    LDS(n: Number) = 0x06, 0x98, Some(1), "Pushes the value at SP + n";
+    LDA(n: Number) = 0x07, 0x7C, Some(0), "Replaces the address on top of the stack by the value at the address + n";
+    LDC(n: Number) = 0x08, 0x84, Some(1), "Pushes n";
+    LDLA(n: Number) = 0x09, 0x8C, Some(1), "Pushes the address MP + n";
+    LDSA(n: Number) = 0x0A, 0x9C, Some(1), "Pushes the address SP + n";
+    LDAA(n: Number) = 0x0B, 0x80, Some(0), "Adds n to the address on top of the stack";
+    BRA(n: Offset) = 0x0C, 0x68, Some(0), "Jumps n words ahead";
+    BRF(n: Offset) = 0x0D, 0x6C, Some(-1), "Pops a value and jumps n words ahead if it is false";
+    BRT(n: Offset) = 0x0E, 0x6D, Some(-1), "Pops a value and jumps n words ahead if it is true";
+    BSR(n: Offset) = 0x0F, 0x70, Some(1), "Pushes the return address and jumps n words ahead";
+    ADD = 0x10, 0x01, Some(-1), "Pops two values and pushes their sum";
+    SUB = 0x11, 0x0C, Some(-1), "Pops two values and pushes the first minus the second";
+    MUL = 0x12, 0x08, Some(-1), "Pops two values and pushes their product";
+    DIV = 0x13, 0x04, Some(-1), "Pops two values and pushes the first divided by the second";
+    MOD = 0x14, 0x07, Some(-1), "Pops two values and pushes the remainder of the first divided by the second";
+    EQ = 0x15, 0x0E, Some(-1), "Pops two values and pushes whether they are equal";
+    NE = 0x16, 0x0F, Some(-1), "Pops two values and pushes whether they differ";
+    LT = 0x17, 0x10, Some(-1), "Pops two values and pushes whether the first is less than the second";
+    LE = 0x18, 0x12, Some(-1), "Pops two values and pushes whether the first is at most the second";
+    GT = 0x19, 0x11, Some(-1), "Pops two values and pushes whether the first is greater than the second";
+    GE = 0x1A, 0x13, Some(-1), "Pops two values and pushes whether the first is at least the second";
+    NEG = 0x1B, 0x20, Some(0), "Negates the value on top of the stack";
+    NOT = 0x1C, 0x21, Some(0), "Replaces the value on top of the stack by whether it is false";
+    RET = 0x1D, 0xA8, Some(-1), "Pops a return address and jumps to it";
+    UNLINK = 0x1E, 0xCC, None, "Sets SP to MP and pops MP, which ends a stack frame";
+    LINK(n: Number) = 0x1F, 0xA0, None, "Pushes MP, sets MP to SP and reserves n locals";
+    AJS(n: Number) = 0x20, 0x64, None, "Adds n to SP";
+    SWP = 0x21, 0xBC, Some(0), "Swaps the two values on top of the stack";
+    SWPR(r: Register) = 0x22, 0xC0, Some(0), "Swaps the value on top of the stack with a register";
+    SWPRR(r: Register, s: Register) = 0x23, 0xC4, Some(0), "Swaps two registers";
+    LDRR(r: Register, s: Register) = 0x24, 0x94, Some(0), "Copies the second register into the first";
+    JSR = 0x25, 0x78, Some(0), "Pops an address, pushes the return address and jumps to the address";
+    TRAP(n: Number) = 0x26, 0xC8, None, "Calls system function n, like printing or reading a value";
+    NOP = 0x27, 0xA4, Some(0), "Does nothing";
+    HALT = 0x28, 0x74, Some(0), "Stops the machine";
+    AND = 0x29, 0x02, Some(-1), "Pops two values and pushes their bitwise and";
+    OR = 0x2A, 0x09, Some(-1), "Pops two values and pushes their bitwise or";
+    XOR = 0x2B, 0x0D, Some(-1), "Pops two values and pushes their bitwise exclusive or";
+    STH = 0x2C, 0xD6, Some(0), "Pops a value, stores it on the heap and pushes its address";
+    STMH(m: Number) = 0x2D, 0xD8, None, "Moves m values from the stack to the heap and pushes the address of the last";
+    LDH(n: Number) = 0x2E, 0xD0, Some(0), "Replaces the heap address on top of the stack by the value at the address + n";
+    LDMH(n: Number, m: Number) = 0x2F, 0xD4, None, "Replaces the heap address on top of the stack by the m values at the address + n";
+    STMA(n: Number, m: Number) = 0x30, 0xAE, None, "Pops an address, then pops m values and stores them at the address + n";
+    LDMA(n: Number, m: Number) = 0x31, 0x7E, None, "Replaces the address on top of the stack by the m values at the address + n";
+    STML(n: Number, m: Number) = 0x32, 0xB2, None, "Pops m values and stores them at MP + n";
+    STMS(n: Number, m: Number) = 0x33, 0xBA, None, "Pops m values and stores them at SP + n";
+    LDML(n: Number, m: Number) = 0x34, 0x8A, None, "Pushes the m values at MP + n";
+    LDMS(n: Number, m: Number) = 0x35, 0x9A, None, "Pushes the m values at SP + n";
+}
+
+/// A line of a program as the assembler sees it: an instruction, or something that only exists
+/// in the source, like a label.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SourceInstr {
+    Instr(Instr),
+    Label(String),
+    Annote(Reg, i32, i32, Color, String),
+    /// `.word`, `.string` and `.stringz`: words loaded into memory as they are.
+    Word(Vec<i32>),
+    /// `.space n`: `n` words of zeros.
+    Space(i32),
+    /// An instruction or data with operands that refer to labels.
+    Unresolved(Unresolved),
+}
+
+/// Instructions and data with operands that refer to labels, until the labels are resolved.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Unresolved {
+    Instr(UnresolvedInstr),
+    /// `.word`
+    Word(Vec<Operand>),
+    /// `.space`, whose size has to be a constant by the time the code is assembled.
+    Space(Operand),
+}
+
+/// A numeric operand in the source.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Operand {
+    Value(i32),
+    /// An expression that refers to labels.
+    Expr(Expr),
+}
+
+/// An operand made of numbers and labels, like `table+2`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Expr {
+    Number(i32),
+    Label(String),
+    Neg(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+}
+
+/// A line of assembly: an instruction or a directive.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum AsmItem {
+    Instr(SourceInstr),
+    /// `.equ NAME value` defines a constant.
+    Equ(String, Expr),
+    /// `.macro NAME PARAMS...` starts a macro definition, which runs up to the next `.endm`.
+    Macro(String, Vec<String>),
+    EndMacro,
+    /// A use of a macro.
+    Call(String, Vec<Expr>),
+    /// `.include "FILE"` assembles another file in its place.
+    Include(String),
+}
+
+impl Expr {
+    /// Evaluates the expression, or returns the first label that `lookup` does not know.
+    pub fn eval(&self, lookup: &impl Fn(&str) -> Option<i32>) -> Result<i32, String> {
+        match self {
+            Expr::Number(n) => Ok(*n),
+            Expr::Label(name) => lookup(name).ok_or_else(|| name.clone()),
+            Expr::Neg(a) => Ok(a.eval(lookup)?.wrapping_neg()),
+            Expr::Add(a, b) => Ok(a.eval(lookup)?.wrapping_add(b.eval(lookup)?)),
+            Expr::Sub(a, b) => Ok(a.eval(lookup)?.wrapping_sub(b.eval(lookup)?)),
+        }
+    }
+
+    pub fn labels(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => Vec::new(),
+            Expr::Label(name) => vec![name],
+            Expr::Neg(a) => a.labels(),
+            Expr::Add(a, b) | Expr::Sub(a, b) => {
+                let mut labels = a.labels();
+                labels.extend(b.labels());
+                labels
             }
         }
     }
 
+    /// Replaces the labels for which `f` returns an expression.
+    pub fn substitute(&self, f: &impl Fn(&str) -> Option<Expr>) -> Expr {
+        match self {
+            Expr::Number(n) => Expr::Number(*n),
+            Expr::Label(name) => f(name).unwrap_or_else(|| self.clone()),
+            Expr::Neg(a) => Expr::Neg(Box::new(a.substitute(f))),
+            Expr::Add(a, b) => Expr::Add(Box::new(a.substitute(f)), Box::new(b.substitute(f))),
+            Expr::Sub(a, b) => Expr::Sub(Box::new(a.substitute(f)), Box::new(b.substitute(f))),
+        }
+    }
+}
+
+impl Instr {
+    pub fn convert(&self) -> Vec<i32> {
+        self.encode(Encoding::Ssmrs)
+    }
+
+    pub fn encode(&self, encoding: Encoding) -> Vec<i32> {
+        let mut words = vec![self.info().opcode_in(encoding)];
+        words.extend(self.operands());
+        words
+    }
+
+    /// Whether the operand is relative to the address after the instruction.
+    pub fn is_branch(&self) -> bool {
+        self.info().operands.contains(&OperandKind::Offset)
+    }
+
     pub fn instr_size(&self) -> usize {
+        self.info().size()
+    }
+}
+
+impl Operand {
+    pub fn eval(&self, lookup: &impl Fn(&str) -> Option<i32>) -> Result<i32, String> {
+        match self {
+            Operand::Value(n) => Ok(*n),
+            Operand::Expr(expr) => expr.eval(lookup),
+        }
+    }
+
+    pub fn labels(&self) -> Vec<&str> {
+        match self {
+            Operand::Value(_) => Vec::new(),
+            Operand::Expr(expr) => expr.labels(),
+        }
+    }
+}
+
+impl From<Expr> for Operand {
+    fn from(expr: Expr) -> Self {
+        match expr.eval(&|_| None) {
+            Ok(n) => Operand::Value(n),
+            Err(_) => Operand::Expr(expr),
+        }
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::LABEL(_) => 0,
-            Self::Bra(_) => 2,
-            Self::Brf(_) => 2,
-            Self::Brt(_) => 2,
-            Self::Bsr(_) => 2,
-            Self::ANNOTE(_, _, _, _, _) => 0,
-            _ => self.convert().len(),
+            Operand::Value(n) => write!(f, "{}", n),
+            Operand::Expr(expr) => write!(f, "{}", expr),
+        }
+    }
+}
+
+impl Unresolved {
+    /// The numeric operands, in order.
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Unresolved::Instr(instr) => instr.operands(),
+            Unresolved::Word(words) => words.iter().collect(),
+            Unresolved::Space(n) => vec![n],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Unresolved::Instr(instr) => instr.operands_mut(),
+            Unresolved::Word(words) => words.iter_mut().collect(),
+            Unresolved::Space(n) => vec![n],
+        }
+    }
+
+    /// Whether the operand is relative to the address after the instruction.
+    pub fn is_branch(&self) -> bool {
+        match self {
+            Unresolved::Instr(instr) => instr.info().operands.contains(&OperandKind::Offset),
+            Unresolved::Word(_) | Unresolved::Space(_) => false,
+        }
+    }
+
+    /// The number of words this takes in memory. A `.space` that refers to labels takes none.
+    pub fn instr_size(&self) -> usize {
+        match self {
+            Unresolved::Instr(instr) => instr.info().size(),
+            Unresolved::Word(words) => words.len(),
+            Unresolved::Space(Operand::Value(n)) => (*n).max(0) as usize,
+            Unresolved::Space(Operand::Expr(_)) => 0,
+        }
+    }
+
+    /// Fills in the values `eval` gives the operands.
+    pub fn resolve<E>(
+        &self,
+        mut eval: impl FnMut(&Operand) -> Result<i32, E>,
+    ) -> Result<SourceInstr, E> {
+        Ok(match self {
+            Unresolved::Instr(instr) => SourceInstr::Instr(instr.resolve(&mut eval)?),
+            Unresolved::Word(words) => {
+                SourceInstr::Word(words.iter().map(eval).collect::<Result<_, _>>()?)
+            }
+            Unresolved::Space(n) => SourceInstr::Space(eval(n)?),
+        })
+    }
+
+    pub fn name_and_params(&self) -> Vec<String> {
+        match self {
+            Unresolved::Instr(instr) => instr.name_and_params(),
+            Unresolved::Word(words) => vec![
+                String::from(".word"),
+                words
+                    .iter()
+                    .map(Operand::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ],
+            Unresolved::Space(n) => vec![String::from(".space"), n.to_string()],
+        }
+    }
+}
+
+impl From<Unresolved> for SourceInstr {
+    /// Resolves the operands right away when none of them refer to labels.
+    fn from(unresolved: Unresolved) -> Self {
+        let value = |operand: &Operand| match operand {
+            Operand::Value(n) => Ok(*n),
+            Operand::Expr(_) => Err(()),
+        };
+        unresolved
+            .resolve(value)
+            .unwrap_or(SourceInstr::Unresolved(unresolved))
+    }
+}
+
+impl SourceInstr {
+    /// Whether the operand is relative to the address after the instruction.
+    pub fn is_branch(&self) -> bool {
+        match self {
+            SourceInstr::Instr(instr) => instr.is_branch(),
+            SourceInstr::Unresolved(unresolved) => unresolved.is_branch(),
+            _ => false,
+        }
+    }
+
+    /// The number of words this takes in memory.
+    pub fn instr_size(&self) -> usize {
+        match self {
+            SourceInstr::Instr(instr) => instr.instr_size(),
+            SourceInstr::Label(_) | SourceInstr::Annote(_, _, _, _, _) => 0,
+            SourceInstr::Word(words) => words.len(),
+            SourceInstr::Space(n) => (*n).max(0) as usize,
+            SourceInstr::Unresolved(unresolved) => unresolved.instr_size(),
+        }
+    }
+
+    /// The words this puts in memory, or `None` while it refers to labels.
+    pub fn convert(&self) -> Option<Vec<i32>> {
+        self.encode(Encoding::Ssmrs)
+    }
+
+    /// Like [`SourceInstr::convert`], with the opcodes of `encoding`.
+    pub fn encode(&self, encoding: Encoding) -> Option<Vec<i32>> {
+        match self {
+            SourceInstr::Instr(instr) => Some(instr.encode(encoding)),
+            SourceInstr::Label(_) | SourceInstr::Annote(_, _, _, _, _) => Some(Vec::new()),
+            SourceInstr::Word(words) => Some(words.clone()),
+            SourceInstr::Space(n) => Some(vec![0; (*n).max(0) as usize]),
+            SourceInstr::Unresolved(_) => None,
         }
     }
 
     pub fn name_and_params(&self) -> Vec<String> {
         match self {
-            Self::STR(n) => vec![String::from("STR"), n.to_string()],
-            Self::STL(n) => vec![String::from("STL"), n.to_string()],
-            Self::STS(n) => vec![String::from("STS"), n.to_string()],
-            Self::STA(n) => vec![String::from("STA"), n.to_string()],
-            Self::STMH(n) => vec![String::from("STMH"), n.to_string()],
-            Self::STMA(n, m) => vec![String::from("STMA"), n.to_string(), m.to_string()],
-            Self::STML(n, m) => vec![String::from("STMA"), n.to_string(), m.to_string()],
-            Self::STMS(n, m) => vec![String::from("STMA"), n.to_string(), m.to_string()],
-            Self::LDR(n) => vec![String::from("LDR"), n.to_string()],
-            Self::LDL(n) => vec![String::from("LDL"), n.to_string()],
-            Self::LDS(n) => vec![String::from("LDS"), n.to_string()],
-            Self::LDA(n) => vec![String::from("LDA"), n.to_string()],
-            Self::LDC(n) => vec![String::from("LDC"), n.to_string()],
-            Self::LDLA(n) => vec![String::from("LDLA"), n.to_string()],
-            Self::LDSA(n) => vec![String::from("LDSA"), n.to_string()],
-            Self::LDAA(n) => vec![String::from("LDAA"), n.to_string()],
-            Self::LDMH(n, m) => vec![String::from("STMH"), n.to_string(), m.to_string()],
-            Self::LDMA(n, m) => vec![String::from("LDMA"), n.to_string(), m.to_string()],
-            Self::LDML(n, m) => vec![String::from("LDMA"), n.to_string(), m.to_string()],
-            Self::LDMS(n, m) => vec![String::from("LDMA"), n.to_string(), m.to_string()],
-            Self::BRA(n) => vec![String::from("BRA"), n.to_string()],
-            Self::BRF(n) => vec![String::from("BRF"), n.to_string()],
-            Self::BRT(n) => vec![String::from("BRT"), n.to_string()],
-            Self::BSR(n) => vec![String::from("BSR"), n.to_string()],
-            Self::LINK(n) => vec![String::from("LINK"), n.to_string()],
-            Self::AJS(n) => vec![String::from("AJS"), n.to_string()],
-            Self::SWPR(n) => vec![String::from("SWPR"), n.to_string()],
-            Self::SWPRR(n, m) => vec![String::from("SWPRR"), n.to_string(), m.to_string()],
-            Self::LDRR(n, m) => vec![String::from("LDRR"), n.to_string(), m.to_string()],
-            Self::TRAP(n) => vec![String::from("TRAP"), n.to_string()],
-            Self::LABEL(n) => vec![String::from("LABEL"), n.to_string()],
-            Self::Bra(n) => vec![String::from("Bra"), n.to_string()],
-            Self::Brf(n) => vec![String::from("Brf"), n.to_string()],
-            Self::Brt(n) => vec![String::from("Brt"), n.to_string()],
-            Self::Bsr(n) => vec![String::from("Bsr"), n.to_string()],
-            Self::ANNOTE(n, m, o, p, q) => vec![
+            SourceInstr::Instr(instr) => instr.name_and_params(),
+            SourceInstr::Label(n) => vec![String::from("LABEL"), n.to_string()],
+            SourceInstr::Annote(n, m, o, p, q) => vec![
                 String::from("ANNOTE"),
                 n.to_string(),
                 m.to_string(),
@@ -309,137 +621,77 @@ impl Instr {
                 p.to_string(),
                 q.to_string(),
             ],
-            x => vec![x.to_string().trim().to_string()],
-        }
-    }
-}
-
-impl From<&[i32]> for Instr {
-    fn from(v: &[i32]) -> Self {
-        match v[0] {
-            0x00 => Instr::STR(Reg::try_from(v[1]).unwrap()),
-            0x01 => Instr::STL(v[1]),
-            0x02 => Instr::STS(v[1]),
-            0x03 => Instr::STA(v[1]),
-            0x04 => Instr::LDR(Reg::try_from(v[1]).unwrap()),
-            0x05 => Instr::LDL(v[1]),
-            0x06 => Instr::LDS(v[1]),
-            0x07 => Instr::LDA(v[1]),
-            0x08 => Instr::LDC(v[1]),
-            0x09 => Instr::LDLA(v[1]),
-            0x0A => Instr::LDSA(v[1]),
-            0x0B => Instr::LDAA(v[1]),
-            0x0C => Instr::BRA(v[1]),
-            0x0D => Instr::BRF(v[1]),
-            0x0E => Instr::BRT(v[1]),
-            0x0F => Instr::BSR(v[1]),
-            0x10 => Instr::ADD,
-            0x11 => Instr::SUB,
-            0x12 => Instr::MUL,
-            0x13 => Instr::DIV,
-            0x14 => Instr::MOD,
-            0x15 => Instr::EQ,
-            0x16 => Instr::NE,
-            0x17 => Instr::LT,
-            0x18 => Instr::LE,
-            0x19 => Instr::GT,
-            0x1A => Instr::GE,
-            0x1B => Instr::NEG,
-            0x1C => Instr::NOT,
-            0x1D => Instr::RET,
-            0x1E => Instr::UNLINK,
-            0x1F => Instr::LINK(v[1]),
-            0x20 => Instr::AJS(v[1]),
-            0x21 => Instr::SWP,
-            0x22 => Instr::SWPR(Reg::try_from(v[1]).unwrap()),
-            0x23 => Instr::SWPRR(Reg::try_from(v[1]).unwrap(), Reg::try_from(v[2]).unwrap()),
-            0x24 => Instr::LDRR(Reg::try_from(v[1]).unwrap(), Reg::try_from(v[2]).unwrap()),
-            0x25 => Instr::JSR,
-            0x26 => Instr::TRAP(v[1]),
-            0x27 => Instr::NOP,
-            0x28 => Instr::HALT,
-            0x29 => Instr::AND,
-            0x2A => Instr::OR,
-            0x2B => Instr::XOR,
-            0x2C => Instr::STH,
-            0x2D => Instr::STMH(v[1]),
-            0x2E => Instr::LDH(v[1]),
-            0x2F => Instr::LDMH(v[1], v[2]),
-            0x30 => Instr::STMA(v[1], v[2]),
-            0x31 => Instr::LDMA(v[1], v[2]),
-            0x32 => Instr::STML(v[1], v[2]),
-            0x33 => Instr::STMS(v[1], v[2]),
-            0x34 => Instr::LDML(v[1], v[2]),
-            0x35 => Instr::LDMS(v[1], v[2]),
-
-            code => panic!("Invalid instruction! {}", code),
+            SourceInstr::Word(words) => vec![String::from(".word"), join_words(words)],
+            SourceInstr::Space(n) => vec![String::from(".space"), n.to_string()],
+            SourceInstr::Unresolved(unresolved) => unresolved.name_and_params(),
         }
     }
 }
 
+impl From<Instr> for SourceInstr {
+    fn from(instr: Instr) -> Self {
+        SourceInstr::Instr(instr)
+    }
+}
+
 impl Display for Instr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name_and_params().join(" "))
+    }
+}
+
+impl Display for SourceInstr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceInstr::Instr(instr) => write!(f, "{}", instr),
+            SourceInstr::Label(n) => write!(f, "{}:", n),
+            SourceInstr::Annote(a, b, c, d, e) => {
+                write!(f, "ANNOTE {} {} {} {} {}", a, b, c, d, quote(e))
+            }
+            SourceInstr::Word(words) if words.is_empty() => write!(f, ".string \"\""),
+            SourceInstr::Word(words) => write!(f, ".word {}", join_words(words)),
+            SourceInstr::Space(n) => write!(f, ".space {}", n),
+            SourceInstr::Unresolved(unresolved) => {
+                write!(f, "{}", unresolved.name_and_params().join(" "))
+            }
+        }
+    }
+}
+
+/// Puts text in quotes, with the escapes the parser understands.
+fn quote(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\0' => quoted.push_str("\\0"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn join_words(words: &[i32]) -> String {
+    words
+        .iter()
+        .map(i32::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Display for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Instr::STR(r) => write!(f, "STR {}", r),
-            Instr::STL(n) => write!(f, "STL {}", n),
-            Instr::STS(n) => write!(f, "STS {}", n),
-            Instr::STA(n) => write!(f, "STA {}", n),
-            Instr::LDR(r) => write!(f, "LDR {}", r),
-            Instr::LDL(n) => write!(f, "LDL {}", n),
-            Instr::LDS(n) => write!(f, "LDS {}", n),
-            Instr::LDA(n) => write!(f, "LDA {}", n),
-            Instr::LDC(n) => write!(f, "LDC {}", n),
-            Instr::LDLA(n) => write!(f, "LDLA {}", n),
-            Instr::LDSA(n) => write!(f, "LDSA {}", n),
-            Instr::LDAA(n) => write!(f, "LDAA {}", n),
-            Instr::BRA(n) => write!(f, "BRA {}", n),
-            Instr::Bra(n) => write!(f, "BRA {}", n),
-            Instr::BRF(n) => write!(f, "BRF {}", n),
-            Instr::Brf(n) => write!(f, "BRF {}", n),
-            Instr::BRT(n) => write!(f, "BRT {}", n),
-            Instr::Brt(n) => write!(f, "BRT {}", n),
-            Instr::BSR(n) => write!(f, "BSR {}", n),
-            Instr::Bsr(n) => write!(f, "BSR {}", n),
-            Instr::ADD => write!(f, "ADD"),
-            Instr::SUB => write!(f, "SUB"),
-            Instr::MUL => write!(f, "MUL"),
-            Instr::DIV => write!(f, "DIV"),
-            Instr::MOD => write!(f, "MOD"),
-            Instr::EQ => write!(f, "EQ"),
-            Instr::NE => write!(f, "NE"),
-            Instr::LT => write!(f, "LT"),
-            Instr::LE => write!(f, "LE"),
-            Instr::GT => write!(f, "GT"),
-            Instr::GE => write!(f, "GE"),
-            Instr::NEG => write!(f, "NEG"),
-            Instr::NOT => write!(f, "NOT"),
-            Instr::RET => write!(f, "RET"),
-            Instr::UNLINK => write!(f, "UNLINK"),
-            Instr::LINK(n) => write!(f, "LINK {}", n),
-            Instr::AJS(n) => write!(f, "AJS {}", n),
-            Instr::SWP => write!(f, "SWP"),
-            Instr::SWPR(r) => write!(f, "SWPR {}", r),
-            Instr::SWPRR(r1, r2) => write!(f, "SWPRR {}, {}", r1, r2),
-            Instr::LDRR(r1, r2) => write!(f, "LDRR {}, {}", r1, r2),
-            Instr::JSR => write!(f, "JSR"),
-            Instr::TRAP(n) => write!(f, "TRAP {}", n),
-            Instr::NOP => write!(f, "NOP"),
-            Instr::HALT => write!(f, "HALT"),
-            Instr::AND => write!(f, "AND"),
-            Instr::OR => write!(f, "OR"),
-            Instr::XOR => write!(f, "XOR"),
-            Instr::STH => write!(f, "STH"),
-            Instr::STMH(n) => write!(f, "STMH {}", n),
-            Instr::LDH(n) => write!(f, "LDH {}", n),
-            Instr::LDMH(n, m) => write!(f, "LDMH {}, {}", n, m),
-            Instr::STMA(n, m) => write!(f, "STMA {}, {}", n, m),
-            Instr::LDMA(n, m) => write!(f, "LDMA {}, {}", n, m),
-            Instr::STML(n, m) => write!(f, "STML {}, {}", n, m),
-            Instr::STMS(n, m) => write!(f, "STMS {}, {}", n, m),
-            Instr::LDML(n, m) => write!(f, "LDML {}, {}", n, m),
-            Instr::LDMS(n, m) => write!(f, "LDMS {}, {}", n, m),
-            Instr::LABEL(n) => write!(f, "{}:", n),
-            Instr::ANNOTE(a, b, c, d, e) => write!(f, "ANNOTE {} {} {} {} {}", a, b, c, d, e),
+            Expr::Number(n) => write!(f, "{}", n),
+            Expr::Label(name) => write!(f, "{}", name),
+            Expr::Neg(a) => write!(f, "-{}", a),
+            Expr::Add(a, b) => write!(f, "{}+{}", a, b),
+            Expr::Sub(a, b) => write!(f, "{}-{}", a, b),
         }
     }
 }
@@ -478,3 +730,64 @@ impl Display for Color {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Encoding, Instr, UnresolvedInstr, INSTRUCTIONS};
+    use crate::{fault::FaultKind, register::Reg};
+
+    #[test]
+    fn table_matches_instructions() {
+        for (i, info) in INSTRUCTIONS.iter().enumerate() {
+            assert_eq!(info.opcode, i as i32);
+            let words = (0..info.size() as i32)
+                .map(|n| n.min(1))
+                .collect::<Vec<_>>();
+            let words = [vec![info.opcode], words[1..].to_vec()].concat();
+            let instr = Instr::try_from(words.as_slice()).unwrap();
+            assert_eq!(instr.info(), info);
+            assert_eq!(instr.convert(), words);
+            assert_eq!(instr.name_and_params()[0], info.mnemonic);
+            let unresolved = UnresolvedInstr::from(instr.clone());
+            assert_eq!(unresolved.info(), info);
+            assert_eq!(unresolved.name_and_params(), instr.name_and_params());
+            assert_eq!(
+                unresolved.resolve(&mut |operand| operand.eval(&|_| None)),
+                Ok(instr)
+            );
+        }
+        assert_eq!(Instr::LDMH(1, 2).to_string(), "LDMH 1 2");
+        assert_eq!(Instr::STMS(-1, 2).to_string(), "STMS -1 2");
+        assert!(Instr::BSR(0).is_branch() && !Instr::LDC(0).is_branch());
+    }
+
+    #[test]
+    fn java_encoding() {
+        for info in INSTRUCTIONS {
+            assert_eq!(
+                INSTRUCTIONS
+                    .iter()
+                    .filter(|other| other.java_opcode == info.java_opcode)
+                    .count(),
+                1
+            );
+        }
+        let instrs = [
+            Instr::LDC(-3),
+            Instr::LDMA(1, 2),
+            Instr::SWPRR(Reg::MP, Reg::R7),
+        ];
+        for instr in instrs {
+            let words = instr.encode(Encoding::Java);
+            assert_eq!(Instr::decode(&words, Encoding::Java), Ok(instr.clone()));
+            assert_eq!(words[1..], instr.convert()[1..]);
+        }
+        assert_eq!(Instr::LDC(-3).encode(Encoding::Java), [0x84, -3]);
+        assert_eq!(Instr::HALT.encode(Encoding::Java), [0x74]);
+        assert_eq!(
+            Instr::decode(&[0x28], Encoding::Java),
+            Err(FaultKind::InvalidOpcode(0x28))
+        );
+        assert_eq!("java".parse(), Ok(Encoding::Java));
+    }
+}
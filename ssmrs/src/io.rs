@@ -0,0 +1,237 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{BufRead, Write},
+    rc::Rc,
+};
+
+/// The devices the [`Cpu`](crate::Cpu) talks to: program input and output through traps, and a
+/// separate channel for diagnostics such as the verbose execution trace.
+pub trait Io {
+    fn write_int(&mut self, value: i32);
+    fn write_char(&mut self, value: char);
+    /// Returns `None` when no (valid) input is available. Input that is not an integer is
+    /// dropped and reported through [`Io::log`].
+    fn read_int(&mut self) -> Option<i32>;
+    /// Returns `None` when no input is available.
+    fn read_char(&mut self) -> Option<char>;
+    /// Reads up to (and consumes, but does not return) the next newline.
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        loop {
+            match self.read_char() {
+                Some('\n') => return Some(line),
+                Some(c) => line.push(c),
+                None if line.is_empty() => return None,
+                None => return Some(line),
+            }
+        }
+    }
+    fn log(&mut self, message: &str);
+}
+
+/// Reads program input from stdin (or another reader), writes program output to stdout and
+/// diagnostics to stderr.
+pub struct StdIo {
+    input: VecDeque<char>,
+    reader: Box<dyn BufRead>,
+}
+
+impl StdIo {
+    pub fn new() -> Self {
+        Self::with_reader(Box::new(std::io::stdin().lock()))
+    }
+
+    pub fn with_reader(reader: Box<dyn BufRead>) -> Self {
+        Self {
+            input: VecDeque::new(),
+            reader,
+        }
+    }
+
+    fn fill(reader: &mut dyn BufRead, input: &mut VecDeque<char>) -> bool {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => false,
+            Ok(_) => {
+                input.extend(line.chars());
+                true
+            }
+        }
+    }
+}
+
+impl Default for StdIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Io for StdIo {
+    fn write_int(&mut self, value: i32) {
+        println!("{}", value);
+    }
+
+    fn write_char(&mut self, value: char) {
+        print!("{}", value);
+        let _ = std::io::stdout().flush();
+    }
+
+    fn read_int(&mut self) -> Option<i32> {
+        let _ = std::io::stdout().flush();
+        let reader = &mut self.reader;
+        match read_int(&mut self.input, |input| Self::fill(reader, input))? {
+            Ok(value) => Some(value),
+            Err(message) => {
+                self.log(&message);
+                None
+            }
+        }
+    }
+
+    fn read_char(&mut self) -> Option<char> {
+        let _ = std::io::stdout().flush();
+        let reader = &mut self.reader;
+        read_char(&mut self.input, |input| Self::fill(reader, input))
+    }
+
+    fn log(&mut self, message: &str) {
+        eprintln!("{}", message);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buffers {
+    input: VecDeque<char>,
+    output: String,
+    log: Vec<String>,
+}
+
+/// Keeps all input and output in memory. Clones share the same buffers, so a handle can be kept
+/// around to feed input to, and inspect the output of, a [`Cpu`](crate::Cpu) that owns another.
+#[derive(Clone, Debug, Default)]
+pub struct BufferIo(Rc<RefCell<Buffers>>);
+
+impl BufferIo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_input(input: &str) -> Self {
+        let io = Self::new();
+        io.push_input(input);
+        io
+    }
+
+    pub fn push_input(&self, input: &str) {
+        self.0.borrow_mut().input.extend(input.chars());
+    }
+
+    pub fn output(&self) -> String {
+        self.0.borrow().output.clone()
+    }
+
+    pub fn take_output(&self) -> String {
+        std::mem::take(&mut self.0.borrow_mut().output)
+    }
+
+    pub fn logs(&self) -> Vec<String> {
+        self.0.borrow().log.clone()
+    }
+}
+
+impl Io for BufferIo {
+    fn write_int(&mut self, value: i32) {
+        let mut b = self.0.borrow_mut();
+        b.output.push_str(&value.to_string());
+        b.output.push('\n');
+    }
+
+    fn write_char(&mut self, value: char) {
+        self.0.borrow_mut().output.push(value);
+    }
+
+    fn read_int(&mut self) -> Option<i32> {
+        let res = read_int(&mut self.0.borrow_mut().input, |_| false)?;
+        match res {
+            Ok(value) => Some(value),
+            Err(message) => {
+                self.log(&message);
+                None
+            }
+        }
+    }
+
+    fn read_char(&mut self) -> Option<char> {
+        read_char(&mut self.0.borrow_mut().input, |_| false)
+    }
+
+    fn log(&mut self, message: &str) {
+        self.0.borrow_mut().log.push(message.to_string());
+    }
+}
+
+/// Pops the next character, calling `fill` to get more input while the buffer is empty.
+pub fn read_char(
+    input: &mut VecDeque<char>,
+    mut fill: impl FnMut(&mut VecDeque<char>) -> bool,
+) -> Option<char> {
+    while input.is_empty() {
+        if !fill(input) {
+            return None;
+        }
+    }
+    input.pop_front()
+}
+
+/// Skips leading whitespace and consumes the next whitespace separated token, which has to be
+/// a (possibly negative) integer. The whitespace character ending the token is consumed too.
+/// Returns `None` when there is no input, and a message when the token is not an integer.
+pub fn read_int(
+    input: &mut VecDeque<char>,
+    mut fill: impl FnMut(&mut VecDeque<char>) -> bool,
+) -> Option<Result<i32, String>> {
+    loop {
+        while input.front().is_some_and(|c| c.is_whitespace()) {
+            input.pop_front();
+        }
+        if !input.is_empty() || !fill(input) {
+            break;
+        }
+    }
+    let mut token = String::new();
+    while let Some(c) = input.pop_front() {
+        if c.is_whitespace() {
+            break;
+        }
+        token.push(c);
+    }
+    if token.is_empty() {
+        return None;
+    }
+    Some(
+        token
+            .parse()
+            .map_err(|_| format!("expected an integer, got `{}`", token)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferIo, Io};
+
+    #[test]
+    fn buffer_reads() {
+        let mut io = BufferIo::with_input("  12 -3\nxhello world\n");
+        assert_eq!(io.read_int(), Some(12));
+        assert_eq!(io.read_int(), Some(-3));
+        assert_eq!(io.read_char(), Some('x'));
+        assert_eq!(io.read_line(), Some("hello world".to_string()));
+        assert_eq!(io.read_char(), None);
+        io.push_input("abc ");
+        assert_eq!(io.read_int(), None);
+        assert_eq!(io.logs(), ["expected an integer, got `abc`"]);
+        assert_eq!(io.read_int(), None);
+        assert_eq!(io.logs().len(), 1);
+    }
+}
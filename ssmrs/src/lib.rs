@@ -1,13 +1,28 @@
+pub mod assembler;
+pub mod config;
 pub mod cpu;
+pub mod debug;
+pub mod diagnostic;
+pub mod disasm;
+mod expand;
+pub mod fault;
+pub mod formatter;
 pub mod instruction;
+pub mod io;
+pub mod object;
 pub mod parser;
 pub mod register;
+pub mod snapshot;
+pub mod source_map;
 
-pub type Code = Vec<Instr>;
-
-pub const MAX_STACK_SIZE: usize = 2000;
+pub type Code = Vec<SourceInstr>;
 
+pub use assembler::assemble;
 pub use chumsky::Parser;
-pub use cpu::Cpu;
-pub use instruction::Instr;
-pub use parser::parse;
+pub use config::CpuConfig;
+pub use cpu::{Cpu, LoadError};
+pub use debug::{RunLimits, StopReason};
+pub use fault::CpuFault;
+pub use instruction::{Instr, SourceInstr};
+pub use parser::{parse, parse_source};
+pub use snapshot::Snapshot;
@@ -1,35 +1,562 @@
-use std::{fs::read_to_string, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::{read_to_string, File},
+    io::BufReader,
+    path::PathBuf,
+    time::Duration,
+};
 
-use chumsky::Parser as _;
-use clap::{ArgAction, Parser};
-use ssmrs::cpu::Cpu;
+use clap::{ArgAction, Args, Parser, Subcommand};
+use ssmrs::{
+    assembler::{Assembler, Assembly},
+    config::{CpuConfig, DEFAULT_HEAP_SIZE, DEFAULT_STACK_SIZE},
+    cpu::Cpu,
+    debug::{WatchKind, WatchTarget, Watchpoint},
+    diagnostic::{Diagnostic, Sources},
+    disasm::disassemble,
+    formatter::format_source,
+    instruction::Encoding,
+    io::StdIo,
+    object::Object,
+    parser::print,
+    register::Reg,
+    RunLimits, Snapshot, StopReason,
+};
 
 #[derive(Parser, Debug)]
 #[clap(
     name = "ssmrs",
     author = "Julius de Jeu",
-    about = "A simple stack machine"
+    about = "A simple stack machine",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
 struct Cli {
-    #[clap(help = "The file to run")]
-    file: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(
+        help = "The files to run, assembled one after the other into one program",
+        required_unless_present = "resume"
+    )]
+    files: Vec<PathBuf>,
+
+    #[clap(
+        short = 'I',
+        long = "include",
+        value_name = "DIR",
+        help = "Also look for included files in this directory"
+    )]
+    include_paths: Vec<PathBuf>,
 
     #[clap(short, long, action = ArgAction::Count, help = "Increase verbosity")]
     verbosity: u8,
+
+    #[clap(
+        short,
+        long,
+        help = "Read program input from this file instead of stdin"
+    )]
+    input: Option<PathBuf>,
+
+    #[clap(long, default_value_t = DEFAULT_STACK_SIZE, help = "Number of words for code and stack")]
+    stack_size: usize,
+
+    #[clap(long, help = "First heap address [default: the stack size]")]
+    heap_start: Option<usize>,
+
+    #[clap(
+        long,
+        help = "First address after the heap [default: 1048576 words after its start]"
+    )]
+    heap_limit: Option<usize>,
+
+    #[clap(long, help = "Fault on writes to the loaded code")]
+    protect_code: bool,
+
+    #[clap(
+        long,
+        help = "Use the opcodes of ssmrs or of the original Java SSM [default: ssmrs, or that of the object]"
+    )]
+    encoding: Option<Encoding>,
+
+    #[clap(
+        long = "reg",
+        value_name = "REG=VALUE",
+        value_parser = parse_register_value,
+        help = "Set a register after loading the code, e.g. R4=10"
+    )]
+    registers: Vec<(Reg, i32)>,
+
+    #[clap(
+        long = "break",
+        value_name = "ADDR|LABEL",
+        help = "Stop before executing the instruction at this address or label"
+    )]
+    breakpoints: Vec<String>,
+
+    #[clap(
+        long = "watch",
+        value_name = "ADDR|REG",
+        help = "Stop after an instruction changes this memory address or register"
+    )]
+    watchpoints: Vec<WatchTarget>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Save the machine state here when it stops"
+    )]
+    save_snapshot: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with = "files",
+        help = "Continue from a saved machine state instead of loading a file"
+    )]
+    resume: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "Stop with an error after executing this many instructions"
+    )]
+    max_steps: Option<u64>,
+
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        value_parser = parse_seconds,
+        help = "Stop with an error after running for this long"
+    )]
+    timeout: Option<Duration>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Format assembly source files in place
+    Fmt(FmtArgs),
+    /// Turn encoded code back into assembly
+    Disasm(DisasmArgs),
+    /// Assemble files into an object file, which can be run without its source
+    Asm(AsmArgs),
+}
+
+#[derive(Args, Debug)]
+struct FmtArgs {
+    #[clap(help = "The files to format [default: format stdin to stdout]")]
+    files: Vec<PathBuf>,
+
+    #[clap(
+        long,
+        help = "Only list the files that are not formatted, failing if there are any"
+    )]
+    check: bool,
+}
+
+#[derive(Args, Debug)]
+struct DisasmArgs {
+    #[clap(
+        help = "An object, a snapshot, or a list of words separated by whitespace or commas, in decimal or hex"
+    )]
+    file: PathBuf,
+
+    #[clap(
+        long,
+        help = "The opcodes of the code, ssmrs or java [default: ssmrs, or that of the object or snapshot]"
+    )]
+    encoding: Option<Encoding>,
+}
+
+#[derive(Args, Debug)]
+struct AsmArgs {
+    #[clap(
+        required = true,
+        help = "The files to assemble, one after the other into one program"
+    )]
+    files: Vec<PathBuf>,
+
+    #[clap(short, long, value_name = "FILE", help = "Where to write the object")]
+    output: PathBuf,
+
+    #[clap(
+        short = 'I',
+        long = "include",
+        value_name = "DIR",
+        help = "Also look for included files in this directory"
+    )]
+    include_paths: Vec<PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "ADDR|LABEL",
+        help = "Start executing here instead of at address 0"
+    )]
+    entry: Option<String>,
+
+    #[clap(long, help = "Leave out the source map")]
+    strip: bool,
+
+    #[clap(
+        long,
+        default_value_t = Encoding::Ssmrs,
+        help = "Use the opcodes of ssmrs or of the original Java SSM"
+    )]
+    encoding: Encoding,
+
+    #[clap(long, default_value_t = DEFAULT_STACK_SIZE, help = "Number of words the code must fit in")]
+    stack_size: usize,
+}
+
+impl Cli {
+    fn config(&self) -> CpuConfig {
+        let heap_start = self.heap_start.unwrap_or(self.stack_size);
+        CpuConfig {
+            stack_size: self.stack_size,
+            heap_start,
+            heap_limit: self
+                .heap_limit
+                .unwrap_or(heap_start.saturating_add(DEFAULT_HEAP_SIZE)),
+            protect_code: self.protect_code,
+            encoding: self.encoding.unwrap_or_default(),
+            // there is no way to step back from the command line
+            history_limit: 0,
+            initial_registers: self.registers.iter().copied().collect(),
+        }
+    }
+}
+
+impl AsmArgs {
+    /// Like [`Cli::config`] with the default heap, right after the stack.
+    fn config(&self) -> CpuConfig {
+        CpuConfig {
+            stack_size: self.stack_size,
+            heap_start: self.stack_size,
+            heap_limit: self.stack_size.saturating_add(DEFAULT_HEAP_SIZE),
+            encoding: self.encoding,
+            ..CpuConfig::default()
+        }
+    }
+}
+
+fn parse_register_value(s: &str) -> Result<(Reg, i32), String> {
+    let (reg, value) = s.split_once('=').ok_or("expected REG=VALUE")?;
+    let reg = reg.trim().parse()?;
+    let value = value.trim().parse().map_err(|e| format!("{}", e))?;
+    Ok((reg, value))
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let seconds: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("{}", e))
+}
+
+fn fail(message: impl Display) -> ! {
+    eprintln!("error: {}", message);
+    std::process::exit(1);
+}
+
+fn format(args: &FmtArgs) {
+    let format = |name: &str, source: &str| {
+        format_source(source).unwrap_or_else(|diagnostics| {
+            for diagnostic in diagnostics {
+                eprintln!("{}\n", diagnostic.render(name, source));
+            }
+            std::process::exit(1);
+        })
+    };
+    if args.files.is_empty() {
+        let source = std::io::read_to_string(std::io::stdin()).unwrap_or_else(|e| fail(e));
+        let formatted = format("<stdin>", &source);
+        if args.check && formatted != source {
+            std::process::exit(1);
+        }
+        if !args.check {
+            print!("{}", formatted);
+        }
+        return;
+    }
+    let mut unformatted = false;
+    for file in &args.files {
+        let name = file.display().to_string();
+        let source =
+            read_to_string(file).unwrap_or_else(|e| fail(format!("cannot read {}: {}", name, e)));
+        let formatted = format(&name, &source);
+        if formatted == source {
+            continue;
+        }
+        if args.check {
+            println!("{}", name);
+            unformatted = true;
+        } else {
+            std::fs::write(file, formatted)
+                .unwrap_or_else(|e| fail(format!("cannot write {}: {}", name, e)));
+        }
+    }
+    if unformatted {
+        std::process::exit(1);
+    }
+}
+
+fn disasm(args: &DisasmArgs) {
+    let bytes = std::fs::read(&args.file)
+        .unwrap_or_else(|e| fail(format!("cannot read {}: {}", args.file.display(), e)));
+    let (words, labels, encoding) = if Object::is_object(&bytes) {
+        let object = Object::from_bytes(&bytes).unwrap_or_else(|e| fail(e));
+        (object.words, object.symbols, object.encoding)
+    } else {
+        let text = String::from_utf8_lossy(&bytes);
+        if text.trim_start().starts_with('{') {
+            let snapshot = Snapshot::from_json(&text).unwrap_or_else(|e| fail(e));
+            (
+                snapshot.memory[..snapshot.code_size].to_vec(),
+                snapshot.labels,
+                snapshot.config.encoding,
+            )
+        } else {
+            (
+                parse_words(&text).unwrap_or_else(|e| fail(e)),
+                BTreeMap::new(),
+                Encoding::Ssmrs,
+            )
+        }
+    };
+    let encoding = args.encoding.unwrap_or(encoding);
+    print!("{}", print(&disassemble(&words, &labels, encoding)));
+}
+
+fn parse_words(text: &str) -> Result<Vec<i32>, String> {
+    text.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let (negative, digits) = match word.strip_prefix('-') {
+                Some(digits) => (true, digits),
+                None => (false, word),
+            };
+            // Parsed unsigned, so that `-2147483648` fits before it is negated.
+            let value = match digits.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => digits.parse::<u32>(),
+            }
+            .map_err(|_| format!("invalid word `{}`", word))? as i32;
+            Ok(if negative {
+                value.wrapping_neg()
+            } else {
+                value
+            })
+        })
+        .collect()
+}
+
+/// Assembles the files, reporting any problems, and exits if they cannot be assembled.
+fn assemble_files(
+    files: &[PathBuf],
+    include_paths: &[PathBuf],
+    verbosity: u8,
+) -> (Assembly, Sources) {
+    let files = files
+        .iter()
+        .map(|file| {
+            if verbosity >= 1 {
+                eprintln!("{}", file.display());
+            }
+            let source = read_to_string(file)
+                .unwrap_or_else(|e| fail(format!("cannot read {}: {}", file.display(), e)));
+            (file.display().to_string(), source)
+        })
+        .collect::<Vec<_>>();
+    let mut assembler = Assembler::new();
+    assembler.include_paths = include_paths.to_vec();
+    let files = files
+        .iter()
+        .map(|(name, source)| (name.as_str(), source.as_str()))
+        .collect::<Vec<_>>();
+    let result = assembler.assemble(&files);
+    let sources = assembler.sources().clone();
+    let report = |diagnostics: &[Diagnostic]| {
+        for diagnostic in diagnostics {
+            eprintln!("{}\n", sources.render(diagnostic));
+        }
+    };
+    let assembly = result.unwrap_or_else(|diagnostics| {
+        report(&diagnostics);
+        std::process::exit(1);
+    });
+    report(&assembly.warnings);
+    (assembly, sources)
+}
+
+/// Reads the files as an object, if they are one.
+fn read_object(files: &[PathBuf]) -> Option<Object> {
+    let objects = files
+        .iter()
+        .filter_map(|file| {
+            let bytes = std::fs::read(file)
+                .unwrap_or_else(|e| fail(format!("cannot read {}: {}", file.display(), e)));
+            Object::is_object(&bytes).then_some((file, bytes))
+        })
+        .collect::<Vec<_>>();
+    match objects.as_slice() {
+        [] => None,
+        [(file, bytes)] if files.len() == 1 => Some(
+            Object::from_bytes(bytes)
+                .unwrap_or_else(|e| fail(format!("cannot load {}: {}", file.display(), e))),
+        ),
+        _ => fail("an object file cannot be combined with other files"),
+    }
+}
+
+fn asm(args: &AsmArgs) {
+    let (assembly, _) = assemble_files(&args.files, &args.include_paths, 0);
+    let mut cpu =
+        Cpu::with_config(args.config(), 0, Box::new(StdIo::new())).unwrap_or_else(|e| fail(e));
+    cpu.load_assembly(assembly).unwrap_or_else(|e| fail(e));
+    let mut object = cpu.object();
+    if let Some(entry) = &args.entry {
+        object.entry = match entry.parse() {
+            Ok(addr) => addr,
+            Err(_) => *object
+                .symbols
+                .get(entry)
+                .unwrap_or_else(|| fail(format!("unknown label {}", entry))),
+        };
+        if !object.entry_is_valid() {
+            fail(format!(
+                "entry point {} is outside the program, which has {} words",
+                object.entry,
+                object.words.len()
+            ));
+        }
+    }
+    if args.strip {
+        object.source_map = None;
+    }
+    std::fs::write(&args.output, object.to_bytes())
+        .unwrap_or_else(|e| fail(format!("cannot write {}: {}", args.output.display(), e)));
 }
 
 fn main() {
     let res = Cli::parse();
-    println!("{}", res.file.display());
-    let code = read_to_string(res.file).unwrap();
-    let c = ssmrs::parse().parse(code).unwrap();
-    let mut cpu = Cpu::new(res.verbosity, Box::new(|s| println!("{}", s)));
-    cpu.load_code(c);
-    while cpu.step() {}
-    if res.verbosity >= 1 {
-        println!("machine halted");
+    match &res.command {
+        Some(Command::Fmt(args)) => return format(args),
+        Some(Command::Disasm(args)) => return disasm(args),
+        Some(Command::Asm(args)) => return asm(args),
+        None => {}
+    }
+    let io = match &res.input {
+        Some(path) => {
+            let file = File::open(path)
+                .unwrap_or_else(|e| fail(format!("cannot read {}: {}", path.display(), e)));
+            StdIo::with_reader(Box::new(BufReader::new(file)))
+        }
+        None => StdIo::new(),
+    };
+    // The assembled files, to show where faults happen.
+    let mut sources = Sources::default();
+    let mut cpu = match &res.resume {
+        Some(path) => {
+            let json = read_to_string(path).unwrap_or_else(|e| fail(e));
+            let snapshot = Snapshot::from_json(&json).unwrap_or_else(|e| fail(e));
+            Cpu::from_snapshot(snapshot, res.verbosity, Box::new(io)).unwrap_or_else(|e| fail(e))
+        }
+        None => {
+            let object = read_object(&res.files);
+            let mut config = res.config();
+            if let (Some(object), None) = (&object, res.encoding) {
+                config.encoding = object.encoding;
+            }
+            let mut cpu =
+                Cpu::with_config(config, res.verbosity, Box::new(io)).unwrap_or_else(|e| fail(e));
+            match object {
+                Some(object) => cpu.load_object(object),
+                None => {
+                    let assembly;
+                    (assembly, sources) =
+                        assemble_files(&res.files, &res.include_paths, res.verbosity);
+                    cpu.load_assembly(assembly)
+                }
+            }
+            .unwrap_or_else(|e| fail(e));
+            cpu
+        }
+    };
+    for location in &res.breakpoints {
+        match location.parse() {
+            Ok(addr) => cpu.add_breakpoint(addr),
+            Err(_) => {
+                cpu.add_label_breakpoint(location)
+                    .unwrap_or_else(|| fail(format!("unknown label {}", location)));
+            }
+        }
+    }
+    for &target in &res.watchpoints {
+        cpu.add_watchpoint(Watchpoint::new(target, WatchKind::Change));
+    }
+    let limits = RunLimits {
+        max_steps: res.max_steps,
+        timeout: res.timeout,
+    };
+    let reason = cpu.run_until(limits);
+    if let Some(path) = &res.save_snapshot {
+        std::fs::write(path, cpu.snapshot().to_json()).unwrap_or_else(|e| fail(e));
+    }
+    match reason {
+        StopReason::Halted => {
+            if res.verbosity >= 1 {
+                eprintln!("machine halted");
+            }
+        }
+        StopReason::WaitingForInput => fail("no input available"),
+        StopReason::Fault(fault) => {
+            let location = cpu.source_map().get(fault.pc);
+            let file = location.and_then(|l| sources.files().iter().find(|f| f.name == l.file));
+            if let (Some(location), Some(file)) = (location, file) {
+                let diagnostic = Diagnostic::error(location.span.clone(), fault.to_string());
+                eprintln!("{}", diagnostic.render(&file.name, &file.text));
+                std::process::exit(1);
+            }
+            match location {
+                Some(location) => fail(format!("{} ({})", fault, location)),
+                None => fail(fault),
+            }
+        }
+        StopReason::StepLimit { .. } | StopReason::Timeout { .. } => fail(reason),
+        reason => {
+            eprintln!("stopped: {}", reason);
+            eprintln!("{:?}", cpu.read_registers());
+            return;
+        }
     }
     if res.verbosity >= 2 {
-        println!("{:?}", cpu.read_registers());
+        eprintln!("{:?}", cpu.read_registers());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::{parse_words, Cli, Command};
+
+    #[test]
+    fn parses_words() {
+        assert_eq!(
+            parse_words("1, -2\n0x10 -0x1 -2147483648 0xffffffff"),
+            Ok(vec![1, -2, 16, -1, i32::MIN, -1])
+        );
+        assert_eq!(parse_words("1 {"), Err("invalid word `{`".to_string()));
+    }
+
+    #[test]
+    fn asm_heap_follows_the_stack() {
+        let cli =
+            Cli::try_parse_from(["ssmrs", "asm", "--stack-size", "5000", "a.ssm", "-o", "a.o"])
+                .unwrap();
+        let Some(Command::Asm(args)) = cli.command else {
+            panic!("expected asm");
+        };
+        assert_eq!(args.config().heap_start, 5000);
+        assert!(args.config().validate().is_ok());
     }
 }
@@ -0,0 +1,302 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use crate::{
+    instruction::Encoding,
+    source_map::{Location, SourceMap},
+};
+
+pub const OBJECT_VERSION: u32 = 2;
+const MAGIC: &[u8; 4] = b"SSMO";
+
+/// An assembled program, which can be loaded without its source.
+///
+/// In a file, all numbers are stored little-endian, with 32 bits each:
+///
+/// - the magic bytes `SSMO`, the version, the [`Encoding`] (`0` for SSMrs, `1` for Java) and the
+///   entry point
+/// - the number of words, followed by the words
+/// - the number of symbols, followed by a name and an address for each
+/// - `1` and the source map, or `0` when there is none. The source map is the number of entries,
+///   followed by an address, a file name, the start and end of the span, the line and the column
+///   for each.
+///
+/// Names are stored as their length in bytes followed by the UTF-8 bytes.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Object {
+    /// The opcodes of the instructions in `words`.
+    pub encoding: Encoding,
+    /// The address execution starts at.
+    pub entry: i32,
+    pub words: Vec<i32>,
+    pub symbols: BTreeMap<String, i32>,
+    pub source_map: Option<SourceMap>,
+}
+
+impl Object {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(MAGIC);
+        put(&mut out, OBJECT_VERSION);
+        put(
+            &mut out,
+            match self.encoding {
+                Encoding::Ssmrs => 0,
+                Encoding::Java => 1,
+            },
+        );
+        put(&mut out, self.entry as u32);
+        put(&mut out, self.words.len() as u32);
+        for &word in &self.words {
+            put(&mut out, word as u32);
+        }
+        put(&mut out, self.symbols.len() as u32);
+        for (name, &addr) in &self.symbols {
+            put_str(&mut out, name);
+            put(&mut out, addr as u32);
+        }
+        match &self.source_map {
+            Some(source_map) => {
+                put(&mut out, 1);
+                put(&mut out, source_map.iter().count() as u32);
+                for (addr, location) in source_map.iter() {
+                    put(&mut out, addr as u32);
+                    put_str(&mut out, &location.file);
+                    put(&mut out, location.span.start as u32);
+                    put(&mut out, location.span.end as u32);
+                    put(&mut out, location.line as u32);
+                    put(&mut out, location.col as u32);
+                }
+            }
+            None => put(&mut out, 0),
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Object, ObjectError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(ObjectError::NotAnObject);
+        }
+        let version = reader.u32()?;
+        if version != OBJECT_VERSION {
+            return Err(ObjectError::UnsupportedVersion(version));
+        }
+        let encoding = match reader.u32()? {
+            0 => Encoding::Ssmrs,
+            1 => Encoding::Java,
+            _ => return Err(ObjectError::Malformed),
+        };
+        let entry = reader.i32()?;
+        let words = (0..reader.u32()?)
+            .map(|_| reader.i32())
+            .collect::<Result<Vec<_>, _>>()?;
+        let symbols = (0..reader.u32()?)
+            .map(|_| Ok((reader.string()?, reader.i32()?)))
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        let source_map = match reader.u32()? {
+            0 => None,
+            1 => {
+                let mut source_map = SourceMap::default();
+                for _ in 0..reader.u32()? {
+                    let addr = reader.i32()?;
+                    let file = reader.string()?;
+                    let span = reader.usize()?..reader.usize()?;
+                    let (line, col) = (reader.usize()?, reader.usize()?);
+                    source_map.insert(
+                        addr,
+                        Location {
+                            file,
+                            span,
+                            line,
+                            col,
+                        },
+                    );
+                }
+                Some(source_map)
+            }
+            _ => return Err(ObjectError::Malformed),
+        };
+        let object = Object {
+            encoding,
+            entry,
+            words,
+            symbols,
+            source_map,
+        };
+        if !reader.bytes.is_empty() || !object.entry_is_valid() {
+            return Err(ObjectError::Malformed);
+        }
+        Ok(object)
+    }
+
+    /// Whether the entry point is the address of a word, or 0 for an empty program.
+    pub fn entry_is_valid(&self) -> bool {
+        match usize::try_from(self.entry) {
+            Ok(entry) => entry < self.words.len() || entry == 0,
+            Err(_) => false,
+        }
+    }
+
+    /// Whether `bytes` start like an object, as opposed to assembly source.
+    pub fn is_object(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+}
+
+fn put(out: &mut Vec<u8>, n: u32) {
+    out.extend(n.to_le_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put(out, s.len() as u32);
+    out.extend(s.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ObjectError> {
+        if n > self.bytes.len() {
+            return Err(ObjectError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, ObjectError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, ObjectError> {
+        self.u32().map(|n| n as i32)
+    }
+
+    fn usize(&mut self) -> Result<usize, ObjectError> {
+        self.u32().map(|n| n as usize)
+    }
+
+    fn string(&mut self) -> Result<String, ObjectError> {
+        let len = self.usize()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| ObjectError::Malformed)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ObjectError {
+    NotAnObject,
+    UnsupportedVersion(u32),
+    Truncated,
+    Malformed,
+}
+
+impl Display for ObjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectError::NotAnObject => write!(f, "not an object file"),
+            ObjectError::UnsupportedVersion(v) => write!(f, "unsupported object version {}", v),
+            ObjectError::Truncated => write!(f, "invalid object file: unexpected end of file"),
+            ObjectError::Malformed => write!(f, "invalid object file"),
+        }
+    }
+}
+
+impl std::error::Error for ObjectError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Object, ObjectError};
+    use crate::{assemble, config::CpuConfig, instruction::Encoding, io::BufferIo, Cpu, LoadError};
+
+    #[test]
+    fn round_trips() {
+        let src = "main: LDC 1\nBRT main\nmsg: .string \"hi\"";
+        let mut cpu = Cpu::new(0, Box::new(BufferIo::new()));
+        cpu.load_assembly(assemble("main.ssm", src).unwrap())
+            .unwrap();
+        let object = cpu.object();
+        assert_eq!(object.words, vec![0x08, 1, 0x0e, -4, 104, 105]);
+        assert_eq!(object.symbols["msg"], 4);
+        let bytes = object.to_bytes();
+        assert_eq!(Object::from_bytes(&bytes), Ok(object.clone()));
+
+        let stripped = Object {
+            source_map: None,
+            ..object.clone()
+        };
+        assert_eq!(
+            Object::from_bytes(&stripped.to_bytes()),
+            Ok(stripped.clone())
+        );
+
+        let mut cpu = Cpu::new(0, Box::new(BufferIo::new()));
+        cpu.load_object(Object {
+            entry: 2,
+            ..stripped
+        })
+        .unwrap();
+        assert_eq!(cpu.read_registers().pc, 2);
+        assert_eq!(cpu.read_registers().sp, 6);
+        assert_eq!(cpu.labels()["main"], 0);
+
+        let config = CpuConfig {
+            encoding: Encoding::Java,
+            ..CpuConfig::default()
+        };
+        let mut cpu = Cpu::with_config(config, 0, Box::new(BufferIo::new())).unwrap();
+        assert_eq!(
+            cpu.load_object(object),
+            Err(LoadError::WrongEncoding {
+                object: Encoding::Ssmrs,
+                cpu: Encoding::Java
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let object = Object {
+            encoding: Encoding::Ssmrs,
+            entry: 0,
+            words: vec![0x28],
+            symbols: [("main".to_string(), 0)].into(),
+            source_map: None,
+        };
+        let bytes = object.to_bytes();
+        assert_eq!(
+            Object::from_bytes(b"LDC 1\n"),
+            Err(ObjectError::NotAnObject)
+        );
+        assert_eq!(
+            Object::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ObjectError::Truncated)
+        );
+        let mut newer = bytes.clone();
+        newer[4] = 3;
+        assert_eq!(
+            Object::from_bytes(&newer),
+            Err(ObjectError::UnsupportedVersion(3))
+        );
+        // Version 1 had no encoding, so its entry point is where the encoding is now.
+        let mut old = vec![];
+        for n in [1, 0, 1, 0x28, 0, 0] {
+            old.extend(u32::to_le_bytes(n));
+        }
+        let old = [b"SSMO".as_slice(), &old].concat();
+        assert_eq!(
+            Object::from_bytes(&old),
+            Err(ObjectError::UnsupportedVersion(1))
+        );
+        let mut outside = bytes.clone();
+        outside[12] = 1;
+        assert_eq!(Object::from_bytes(&outside), Err(ObjectError::Malformed));
+        let empty = Object {
+            words: vec![],
+            ..object
+        };
+        assert_eq!(Object::from_bytes(&empty.to_bytes()), Ok(empty));
+    }
+}
@@ -1,157 +1,345 @@
 use chumsky::{
     prelude::Simple,
-    primitive::{choice, filter, just},
-    text::{self, ident, whitespace, TextParser},
+    primitive::{choice, end, filter, just},
+    recovery::skip_until,
+    text::{self, ident, TextParser},
     Error, Parser,
 };
 
-use crate::Instr;
-use crate::{instruction::Color, register::Reg};
+use std::ops::Range;
 
-pub fn parse() -> impl Parser<char, Vec<Instr>, Error = Simple<char>> {
-    let comment = just("//")
-        .or(just(";"))
-        .then(text::newline().not().repeated())
-        .padded();
+use crate::{diagnostic::Diagnostic, Code, Instr};
+use crate::{
+    instruction::{
+        AsmItem, Color, Expr, InstrInfo, Operand, OperandKind, SourceInstr, Unresolved,
+        UnresolvedInstr, INSTRUCTIONS,
+    },
+    register::Reg,
+};
+
+pub fn parse() -> impl Parser<char, Code, Error = Simple<char>> {
     parse_instr()
-        .padded_by(comment.repeated())
+        .padded_by(comment().repeated())
         .padded()
         .repeated()
 }
 
-fn parse_instr() -> impl Parser<char, Instr, Error = Simple<char>> {
-    let number = just('-')
-        .or_not()
-        .chain::<char, _, _>(text::int(10))
-        .collect::<String>()
-        .from_str()
-        .unwrapped()
-        .labelled("number");
+/// Prints code so that [`parse`] reads it back as the same code.
+pub fn print(code: &[SourceInstr]) -> String {
+    code.iter()
+        .map(|instr| match instr {
+            SourceInstr::Label(_) => format!("{}\n", instr),
+            instr => format!("    {}\n", instr),
+        })
+        .collect()
+}
+
+/// Parses a whole program. Unlike [`parse`], this does not stop at the first line it cannot
+/// parse, but skips it and reports every bad line.
+pub fn parse_source(source: &str) -> Result<Vec<AsmItem>, Vec<Diagnostic>> {
+    parse_spanned(source).map(|items| items.into_iter().map(|(item, _)| item).collect())
+}
+
+/// Like [`parse_source`], but keeps the span of every item.
+pub fn parse_spanned(source: &str) -> Result<Vec<(AsmItem, Range<usize>)>, Vec<Diagnostic>> {
+    let (code, errors) = parse_item()
+        .map_with_span(|instr, span| Some((instr, span)))
+        .recover_with(skip_until(['\n'], |_| None))
+        .padded_by(comment().repeated())
+        .padded()
+        .repeated()
+        .then_ignore(comment().repeated().padded())
+        .then_ignore(end())
+        .parse_recovery(source);
+    if errors.is_empty() {
+        Ok(code.into_iter().flatten().flatten().collect())
+    } else {
+        Err(errors
+            .iter()
+            .map(|e| Diagnostic::from_parse_error(e, source))
+            .collect())
+    }
+}
 
+fn parse_item() -> impl Parser<char, AsmItem, Error = Simple<char>> {
+    let name = || text::ident().labelled("name");
     choice((
-        i("STR", Instr::STR, parse_register()),
-        i("STL", Instr::STL, number),
-        i("STS", Instr::STS, number),
-        i("STA", Instr::STA, number),
-        i("STMH", Instr::STMH, number),
-        i("LDR", Instr::LDR, parse_register()),
-        i("LDL", Instr::LDL, number),
-        i("LDS", Instr::LDS, number),
-        i("LDA", Instr::LDA, number),
-        i("LDC", Instr::LDC, number),
-        i("LDLA", Instr::LDLA, number),
-        i("LDSA", Instr::LDSA, number),
-        i("LDAA", Instr::LDAA, number),
-        i("LDH", Instr::LDH, number),
-        i("BRA", Instr::Bra, text::ident()),
-        i("BRF", Instr::Brf, text::ident()),
-        i("BRT", Instr::Brt, text::ident()),
-        i("BSR", Instr::Bsr, text::ident()),
-        i("LINK", Instr::LINK, number),
-        i("AJS", Instr::AJS, number),
-        i("SWPR", Instr::SWPR, parse_register()),
-        i("TRAP", Instr::TRAP, number),
+        directive("equ")
+            .ignore_then(inline_whitespace())
+            .ignore_then(name())
+            .then_ignore(inline_whitespace())
+            .then(operand())
+            .map(|(name, value)| AsmItem::Equ(name, value)),
+        directive("macro")
+            .ignore_then(inline_whitespace())
+            .ignore_then(name())
+            .then(inline_whitespace().ignore_then(name()).repeated())
+            .map(|(name, params)| AsmItem::Macro(name, params)),
+        directive("endm").to(AsmItem::EndMacro),
+        directive("include")
+            .ignore_then(inline_whitespace())
+            .ignore_then(
+                filter(|c: &char| *c != '"' && *c != '\n')
+                    .repeated()
+                    .delimited_by(just('"'), just('"'))
+                    .collect::<String>()
+                    .labelled("file name"),
+            )
+            .map(AsmItem::Include),
+        parse_instr().map(AsmItem::Instr),
+        // Checked before consuming the name, like in `instr`.
+        text::ident()
+            .rewind()
+            .try_map(|name: String, span| {
+                if is_mnemonic(&name) {
+                    Err(Simple::expected_input_found(span, None, None))
+                } else {
+                    Ok(())
+                }
+            })
+            .ignore_then(text::ident())
+            .then(inline_whitespace().ignore_then(operand()).repeated())
+            .map(|(name, args)| AsmItem::Call(name, args)),
     ))
-    .or(choice((
-        s("ADD", Instr::ADD),
-        s("SUB", Instr::SUB),
-        s("MUL", Instr::MUL),
-        s("DIV", Instr::DIV),
-        s("MOD", Instr::MOD),
-        s("EQ", Instr::EQ),
-        s("NE", Instr::NE),
-        s("LT", Instr::LT),
-        s("LE", Instr::LE),
-        s("GT", Instr::GT),
-        s("GE", Instr::GE),
-        s("NEG", Instr::NEG),
-        s("NOT", Instr::NOT),
-        s("RET", Instr::RET),
-        s("UNLINK", Instr::UNLINK),
-        s("SWP", Instr::SWP),
-        s("JSR", Instr::JSR),
-        s("NOP", Instr::NOP),
-        s("HALT", Instr::HALT),
-        s("AND", Instr::AND),
-        s("OR", Instr::OR),
-        s("XOR", Instr::XOR),
-        s("STH", Instr::STH),
-    )))
-    .or(choice((
-        w("SWPRR", Instr::SWPRR, parse_register(), parse_register()),
-        w("LDRR", Instr::LDRR, parse_register(), parse_register()),
-        w("STMA", Instr::STMA, number, number),
-        w("STML", Instr::STML, number, number),
-        w("STMS", Instr::STMS, number, number),
-        w("LDMA", Instr::LDMA, number, number),
-        w("LDMH", Instr::LDMH, number, number),
-        w("LDML", Instr::LDML, number, number),
-        w("LDMS", Instr::LDMS, number, number),
-        i("BRA", Instr::BRA, number),
-        i("BRF", Instr::BRF, number),
-        i("BRT", Instr::BRT, number),
-        i("BSR", Instr::BSR, number),
-        a(number),
+    .labelled("instruction")
+}
+
+/// Whitespace between operands, which may not continue on the next line.
+fn inline_whitespace() -> impl Parser<char, (), Error = Simple<char>> + Clone {
+    filter(|c: &char| c.is_whitespace() && *c != '\n')
+        .repeated()
+        .ignored()
+}
+
+fn comment() -> impl Parser<char, (), Error = Simple<char>> + Clone {
+    just("//")
+        .or(just(";"))
+        .then(text::newline().not().repeated())
+        .padded()
+        .ignored()
+}
+
+pub(crate) fn is_mnemonic(name: &str) -> bool {
+    name.eq_ignore_ascii_case("ANNOTE")
+        || INSTRUCTIONS
+            .iter()
+            .any(|info| info.mnemonic.eq_ignore_ascii_case(name))
+}
+
+fn parse_instr() -> impl Parser<char, SourceInstr, Error = Simple<char>> {
+    let instrs = INSTRUCTIONS
+        .iter()
+        .map(|info| {
+            let mut operands = instr(info.mnemonic).to(Vec::new()).boxed();
+            for &kind in info.operands {
+                operands = operands
+                    .then_ignore(inline_whitespace())
+                    .then(operand_of(kind))
+                    .map(|(mut operands, operand)| {
+                        operands.push(operand);
+                        operands
+                    })
+                    .boxed();
+            }
+            operands.map(move |operands| build(info, operands)).boxed()
+        })
+        .collect::<Vec<_>>();
+    choice(instrs)
+        .or(a(number().labelled("number")))
+        .or(data())
+        .or(text::ident().then_ignore(just(":")).map(SourceInstr::Label))
+        .labelled("instruction")
+}
+
+#[derive(Clone)]
+enum Arg {
+    Register(Reg),
+    Expr(Expr),
+}
+
+fn operand_of(kind: OperandKind) -> impl Parser<char, Arg, Error = Simple<char>> {
+    match kind {
+        OperandKind::Number | OperandKind::Offset => operand().map(Arg::Expr).boxed(),
+        OperandKind::Register => parse_register().map(Arg::Register).boxed(),
+    }
+}
+
+/// Makes an instruction from its parsed operands, which are in the order of `info.operands`.
+fn build(info: &InstrInfo, args: Vec<Arg>) -> SourceInstr {
+    let mut words = vec![info.opcode];
+    let mut exprs = Vec::new();
+    for arg in args {
+        match arg {
+            Arg::Register(reg) => words.push(reg as i32),
+            Arg::Expr(expr) => {
+                exprs.push(expr);
+                words.push(0);
+            }
+        }
+    }
+    let instr = Instr::try_from(words.as_slice()).expect("the operands match the table");
+    let mut instr = UnresolvedInstr::from(instr);
+    for (operand, expr) in instr.operands_mut().into_iter().zip(exprs) {
+        *operand = expr.into();
+    }
+    Unresolved::Instr(instr).into()
+}
+
+fn directive(name: &'static str) -> impl Parser<char, (), Error = Simple<char>> + Clone {
+    just('.').ignore_then(instr(name))
+}
+
+/// `.word`, `.string`, `.stringz` and `.space`, which put data in memory.
+fn data() -> impl Parser<char, SourceInstr, Error = Simple<char>> {
+    let string = |name, terminator: Option<i32>| {
+        directive(name)
+            .ignore_then(inline_whitespace())
+            .ignore_then(string_literal())
+            .map(move |s| {
+                SourceInstr::Word(s.chars().map(|c| c as i32).chain(terminator).collect())
+            })
+    };
+    let comma = inline_whitespace()
+        .then(just(','))
+        .then(inline_whitespace());
+    choice((
+        directive("word")
+            .ignore_then(inline_whitespace())
+            .ignore_then(operand())
+            .then(comma.clone().ignore_then(operand()).repeated())
+            // Otherwise a comma without an operand would only be reported as an unexpected `,`.
+            .then_ignore(comma.or_not().try_map(|comma, span| match comma {
+                Some(_) => Err(Simple::custom(span, "expected operand after `,`")),
+                None => Ok(()),
+            }))
+            .map(|(first, mut words)| {
+                words.insert(0, first);
+                Unresolved::Word(words.into_iter().map(Operand::from).collect()).into()
+            }),
+        string("string", None),
+        string("stringz", Some(0)),
+        directive("space")
+            .ignore_then(inline_whitespace())
+            .ignore_then(operand())
+            .map(|size| Unresolved::Space(size.into()).into()),
+    ))
+}
+
+/// A decimal, hex (`0x`) or binary (`0b`) number that fits in 32 bits, or a character literal.
+fn number() -> impl Parser<char, i32, Error = Simple<char>> + Clone {
+    let digits = |radix: u32| {
+        filter(move |c: &char| c.is_digit(radix))
+            .repeated()
+            .at_least(1)
+            .collect::<String>()
+            .map(move |digits| (digits, radix))
+    };
+    let literal = just("0x")
+        .or(just("0X"))
+        .ignore_then(digits(16))
+        .or(just("0b").or(just("0B")).ignore_then(digits(2)))
+        .or(text::int(10).map(|digits| (digits, 10)));
+    just('-')
+        .or_not()
+        .then(literal)
+        .try_map(|(sign, (digits, radix)), span| {
+            i64::from_str_radix(&digits, radix)
+                .ok()
+                .map(|n| if sign.is_some() { -n } else { n })
+                .and_then(|n| i32::try_from(n).ok())
+                .ok_or_else(|| Simple::custom(span, "number does not fit in 32 bits"))
+        })
+        .or(char_literal())
+}
+
+fn escape() -> impl Parser<char, char, Error = Simple<char>> + Clone {
+    just('\\').ignore_then(choice((
+        just('n').to('\n'),
+        just('t').to('\t'),
+        just('r').to('\r'),
+        just('0').to('\0'),
+        just('\\'),
+        just('\''),
+        just('"'),
     )))
-    .or(text::ident().then_ignore(just(":")).map(Instr::LABEL))
 }
 
-fn instr(s: &'static str) -> impl Parser<char, (), Error = Simple<char>> {
-    ident().try_map(move |st: String, span| {
-        st.eq_ignore_ascii_case(s)
-            .then_some(())
-            .ok_or_else(|| Simple::expected_input_found(span, None, None))
-    })
+fn char_literal() -> impl Parser<char, i32, Error = Simple<char>> + Clone {
+    filter(|c: &char| *c != '\\' && *c != '\'' && *c != '\n')
+        .or(escape())
+        .delimited_by(just('\''), just('\''))
+        .map(|c| c as i32)
+}
+
+fn string_literal() -> impl Parser<char, String, Error = Simple<char>> + Clone {
+    filter(|c: &char| *c != '\\' && *c != '"' && *c != '\n')
+        .or(escape())
+        .repeated()
+        .delimited_by(just('"'), just('"'))
+        .collect::<String>()
+        .labelled("string")
 }
 
-fn i<A>(
-    s: &'static str,
-    f: impl Fn(A) -> Instr,
-    p: impl Parser<char, A, Error = Simple<char>>,
-) -> impl Parser<char, Instr, Error = Simple<char>> {
-    instr(s).ignore_then(whitespace()).ignore_then(p).map(f)
+/// A number, a label, or a sum or difference of them without spaces, like `table+2`.
+fn operand() -> impl Parser<char, Expr, Error = Simple<char>> + Clone {
+    let label = text::ident().map(Expr::Label);
+    let term = number()
+        .map(Expr::Number)
+        .or(label)
+        .or(just('-').ignore_then(label).map(|e| Expr::Neg(Box::new(e))))
+        .labelled("operand");
+    let op = just('+')
+        .to(Expr::Add as fn(_, _) -> _)
+        .or(just('-').to(Expr::Sub as fn(_, _) -> _));
+    term.clone()
+        .then(op.then(term).repeated())
+        .foldl(|a, (op, b)| op(Box::new(a), Box::new(b)))
 }
 
-fn s<T: Clone>(s: &'static str, i: T) -> impl Parser<char, T, Error = Simple<char>> {
-    instr(s).to(i)
+/// Matches a mnemonic. A mismatch is reported at the start of the word, so that it does not
+/// hide the errors of the instruction that did match.
+fn instr(s: &'static str) -> impl Parser<char, (), Error = Simple<char>> + Clone {
+    ident()
+        .rewind()
+        .try_map(move |st: String, span| {
+            st.eq_ignore_ascii_case(s)
+                .then_some(())
+                .ok_or_else(|| Simple::expected_input_found(span, None, None))
+        })
+        .ignore_then(ident())
+        .ignored()
 }
 
-fn w<A, B>(
-    s: &'static str,
-    f: impl Fn(A, B) -> Instr + 'static,
-    p: impl Parser<char, A, Error = Simple<char>>,
-    q: impl Parser<char, B, Error = Simple<char>>,
-) -> impl Parser<char, Instr, Error = Simple<char>> {
-    instr(s)
-        .ignore_then(whitespace())
-        .ignore_then(p)
-        .then_ignore(whitespace())
-        .then(q)
-        .map(move |(a, b)| f(a, b))
+fn s<T: Clone>(s: &'static str, i: T) -> impl Parser<char, T, Error = Simple<char>> {
+    instr(s).to(i)
 }
 
 fn a(
     number: impl Parser<char, i32, Error = Simple<char>> + Clone,
-) -> impl Parser<char, Instr, Error = Simple<char>> {
+) -> impl Parser<char, SourceInstr, Error = Simple<char>> {
     instr("annote")
-        .ignore_then(whitespace())
+        .ignore_then(inline_whitespace())
         .ignore_then(parse_register())
-        .then_ignore(whitespace())
+        .then_ignore(inline_whitespace())
         .then(number.clone())
-        .then_ignore(whitespace())
+        .then_ignore(inline_whitespace())
         .then(number)
-        .then_ignore(whitespace())
+        .then_ignore(inline_whitespace())
         .then(parse_color())
-        .then_ignore(whitespace())
+        .then_ignore(inline_whitespace())
         .then(maybe_quoted_text())
-        .map(|((((a, b), c), d), e)| Instr::ANNOTE(a, b, c, d, e))
+        .map(|((((a, b), c), d), e)| SourceInstr::Annote(a, b, c, d, e))
 }
 
+/// Text in quotes, which may use the escapes of strings, or a single word. A backslash that does
+/// not start an escape is kept as it is.
 fn maybe_quoted_text() -> impl Parser<char, String, Error = Simple<char>> {
-    filter(|c| (c != &'"' && c != &'\n' && c != &'\r'))
+    filter(|c| c != &'"' && c != &'\\' && c != &'\n' && c != &'\r')
+        .or(escape())
+        .or(just('\\'))
         .repeated()
         .delimited_by(just("\""), just("\""))
-        .or(filter(|c| (c != &'"' && c != &'\n' && c != &'\r' && c != &' ')).repeated())
+        .or(filter(|c| c != &'"' && c != &'\n' && c != &'\r' && c != &' ').repeated())
         .map(|v| v.into_iter().collect())
 }
 
@@ -171,6 +359,7 @@ fn parse_register() -> impl Parser<char, Reg, Error = Simple<char>> {
         just("R6").to(Reg::R6),
         just("R7").to(Reg::R7),
     ))
+    .labelled("register")
 }
 
 fn parse_color() -> impl Parser<char, Color, Error = Simple<char>> {
@@ -188,17 +377,27 @@ fn parse_color() -> impl Parser<char, Color, Error = Simple<char>> {
         s("red", Color::Red),
         s("yellow", Color::Yellow),
     ))
+    .labelled("color")
 }
 
 #[cfg(test)]
 mod tests {
-    use chumsky::Parser;
+    use chumsky::{primitive::end, Parser};
+    use proptest::prelude::*;
+
+    use crate::{
+        instruction::{
+            AsmItem, Color, Expr, Operand, OperandKind, Unresolved, UnresolvedInstr, INSTRUCTIONS,
+        },
+        register::Reg,
+        Code, Instr, SourceInstr,
+    };
 
     #[test]
     fn test_single_instr() {
         let input = "NOP";
         let result = super::parse().parse(input);
-        assert_eq!(result, Ok(vec![super::Instr::NOP]));
+        assert_eq!(result, Ok(vec![super::Instr::NOP.into()]));
     }
 
     #[test]
@@ -215,12 +414,12 @@ main:
         assert_eq!(
             result,
             Ok(vec![
-                super::Instr::LABEL("main".to_string()),
-                super::Instr::LDC(41),
-                super::Instr::LDC(1),
-                super::Instr::ADD,
-                super::Instr::TRAP(0),
-                super::Instr::HALT,
+                super::SourceInstr::Label("main".to_string()),
+                super::Instr::LDC(41).into(),
+                super::Instr::LDC(1).into(),
+                super::Instr::ADD.into(),
+                super::Instr::TRAP(0).into(),
+                super::Instr::HALT.into(),
             ])
         );
     }
@@ -233,7 +432,7 @@ main:
         let result = super::parse().parse(code);
         assert_eq!(
             result,
-            Ok(vec![super::Instr::ANNOTE(
+            Ok(vec![super::SourceInstr::Annote(
                 super::Reg::PC,
                 1,
                 2,
@@ -248,7 +447,7 @@ main:
         let result = super::parse().parse(code);
         assert_eq!(
             result,
-            Ok(vec![super::Instr::ANNOTE(
+            Ok(vec![super::SourceInstr::Annote(
                 super::Reg::PC,
                 1,
                 2,
@@ -263,7 +462,7 @@ main:
         let result = super::parse().parse(code);
         assert_eq!(
             result,
-            Ok(vec![super::Instr::ANNOTE(
+            Ok(vec![super::SourceInstr::Annote(
                 super::Reg::PC,
                 1,
                 2,
@@ -272,4 +471,217 @@ main:
             ),])
         );
     }
+
+    #[test]
+    fn literals() {
+        let src = "LDC 0x7fffffff\nLDC -0x80000000\nLDC 0b1010\nLDC 'a'\nLDC '\\n'+1\nLDC '\\''\nLDC -2147483648";
+        assert_eq!(
+            super::parse_source(src),
+            Ok(vec![
+                super::Instr::LDC(i32::MAX),
+                super::Instr::LDC(i32::MIN),
+                super::Instr::LDC(10),
+                super::Instr::LDC(97),
+                super::Instr::LDC(11),
+                super::Instr::LDC(39),
+                super::Instr::LDC(i32::MIN),
+            ]
+            .into_iter()
+            .map(|instr| AsmItem::Instr(instr.into()))
+            .collect())
+        );
+
+        let src = "LDC 2147483648\nLDC 0x100000000\nAJS 99999999999999999999999\nLDC 'ab'";
+        let errors = super::parse_source(src).unwrap_err();
+        let messages = errors
+            .iter()
+            .map(|e| e.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                "number does not fit in 32 bits",
+                "number does not fit in 32 bits",
+                "number does not fit in 32 bits",
+                "unexpected 'b', expected operand",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_every_bad_line() {
+        let src = "main: LDC 1\n  LDC #\nLDR QQ\n.equ 3\nBRA 1 2\nADD\nLDMA 1\nHALT";
+        let errors = super::parse_source(src).unwrap_err();
+        let messages = errors
+            .iter()
+            .map(|e| {
+                let (line, _) = crate::diagnostic::line_col(src, e.span.start);
+                format!("{}: {}", line, e.message)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                "2: unexpected '#', expected operand",
+                "3: unexpected 'Q', expected register",
+                "4: unexpected '3', expected name",
+                "5: unexpected '2', expected instruction",
+                "7: unexpected end of line, expected operand",
+            ]
+        );
+        assert_eq!(
+            super::parse_source("main: LDC 1 ; one\nHALT\n"),
+            Ok(vec![
+                AsmItem::Instr(super::SourceInstr::Label("main".to_string())),
+                AsmItem::Instr(super::Instr::LDC(1).into()),
+                AsmItem::Instr(super::Instr::HALT.into())
+            ])
+        );
+    }
+
+    #[test]
+    fn directives() {
+        let src = ".equ N -M+1\n.MACRO push2 a b\nLDC a\nLDC b\n.endm\npush2 N 'x'\nHALT";
+        assert_eq!(
+            super::parse_source(src),
+            Ok(vec![
+                AsmItem::Equ(
+                    "N".to_string(),
+                    super::Expr::Add(
+                        Box::new(super::Expr::Neg(Box::new(super::Expr::Label(
+                            "M".to_string()
+                        )))),
+                        Box::new(super::Expr::Number(1))
+                    )
+                ),
+                AsmItem::Macro("push2".to_string(), vec!["a".to_string(), "b".to_string()]),
+                AsmItem::Instr(super::SourceInstr::Unresolved(Unresolved::Instr(
+                    UnresolvedInstr::LDC(Operand::Expr(super::Expr::Label("a".to_string())))
+                ))),
+                AsmItem::Instr(super::SourceInstr::Unresolved(Unresolved::Instr(
+                    UnresolvedInstr::LDC(Operand::Expr(super::Expr::Label("b".to_string())))
+                ))),
+                AsmItem::EndMacro,
+                AsmItem::Call(
+                    "push2".to_string(),
+                    vec![
+                        super::Expr::Label("N".to_string()),
+                        super::Expr::Number(120)
+                    ]
+                ),
+                AsmItem::Instr(super::Instr::HALT.into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn prints_parsable_code() {
+        let code = super::parse().parse("main:\nSWPRR R1 R7\nLDMA -1 2\nANNOTE SP 0 1 blue \"a \\\"b\\\"\"\nLDC main-1\n.word main, 3\n.string \"\"").unwrap();
+        assert_eq!(
+            super::print(&code),
+            "main:\n    SWPRR SP R7\n    LDMA -1 2\n    ANNOTE SP 0 1 blue \"a \\\"b\\\"\"\n    LDC main-1\n    .word main, 3\n    .string \"\"\n"
+        );
+    }
+
+    fn label() -> impl Strategy<Value = String> {
+        "[a-z_][a-z0-9_]{0,6}".prop_filter("not a mnemonic", |name| !super::is_mnemonic(name))
+    }
+
+    /// Operands as the parser produces them: terms joined by `+` and `-`, with at least one label.
+    fn expr() -> impl Strategy<Value = Expr> {
+        let term = prop_oneof![
+            any::<i32>().prop_map(Expr::Number),
+            label().prop_map(Expr::Label),
+            label().prop_map(|l| Expr::Neg(Box::new(Expr::Label(l)))),
+        ];
+        (
+            term.clone(),
+            prop::collection::vec((any::<bool>(), term), 0..3),
+        )
+            .prop_map(|(first, rest)| {
+                rest.into_iter().fold(first, |a, (add, b)| {
+                    if add {
+                        Expr::Add(Box::new(a), Box::new(b))
+                    } else {
+                        Expr::Sub(Box::new(a), Box::new(b))
+                    }
+                })
+            })
+            .prop_filter("has a label", |e| !e.labels().is_empty())
+    }
+
+    fn instr() -> impl Strategy<Value = Instr> {
+        let registers = prop::array::uniform2(0..8);
+        (0..INSTRUCTIONS.len(), any::<[i32; 2]>(), registers).prop_map(|(i, numbers, registers)| {
+            let info = &INSTRUCTIONS[i];
+            let mut words = vec![info.opcode];
+            for (j, kind) in info.operands.iter().enumerate() {
+                words.push(match kind {
+                    OperandKind::Register => registers[j],
+                    _ => numbers[j],
+                });
+            }
+            Instr::try_from(words.as_slice()).unwrap()
+        })
+    }
+
+    fn source_instr() -> impl Strategy<Value = SourceInstr> {
+        let colors = [
+            Color::Black,
+            Color::Blue,
+            Color::Cyan,
+            Color::DarkGray,
+            Color::Gray,
+            Color::Green,
+            Color::LightGray,
+            Color::Magenta,
+            Color::Orange,
+            Color::Pink,
+            Color::Red,
+            Color::Yellow,
+        ];
+        let words = prop::collection::vec(any::<i32>(), 0..4);
+        prop_oneof![
+            instr().prop_map(SourceInstr::Instr),
+            label().prop_map(SourceInstr::Label),
+            (
+                0..8,
+                any::<i32>(),
+                any::<i32>(),
+                prop::sample::select(colors.to_vec()),
+                any::<String>()
+            )
+                .prop_map(|(r, a, b, c, text)| {
+                    SourceInstr::Annote(Reg::try_from(r).unwrap(), a, b, c, text)
+                }),
+            words.clone().prop_map(SourceInstr::Word),
+            (0..i32::MAX).prop_map(SourceInstr::Space),
+            (
+                prop_oneof![
+                    instr().prop_map(|instr| Unresolved::Instr(instr.into())),
+                    words.prop_map(|words| {
+                        Unresolved::Word(words.into_iter().map(Operand::Value).collect())
+                    })
+                ],
+                prop::collection::vec(prop::option::of(expr()), 4),
+            )
+                .prop_map(|(mut unresolved, exprs)| {
+                    for (operand, expr) in unresolved.operands_mut().into_iter().zip(exprs) {
+                        if let Some(expr) = expr {
+                            *operand = expr.into();
+                        }
+                    }
+                    SourceInstr::from(unresolved)
+                }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn print_and_parse_round_trip(code in prop::collection::vec(source_instr(), 0..20)) {
+            let printed = super::print(&code);
+            let parsed: Result<Code, _> = super::parse().then_ignore(end()).parse(printed.as_str());
+            prop_assert_eq!(parsed, Ok(code), "{}", printed);
+        }
+    }
 }
@@ -1,10 +1,12 @@
-use crate::MAX_STACK_SIZE;
+use crate::config::DEFAULT_STACK_SIZE;
+use serde::{Deserialize, Serialize};
 use std::{
     fmt::Display,
     ops::{Index, IndexMut},
+    str::FromStr,
 };
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
 pub struct RegisterFile {
     pub pc: i32,
     pub sp: i32,
@@ -22,7 +24,7 @@ impl RegisterFile {
             pc: 0,
             sp: 0,
             mp: 0,
-            hp: MAX_STACK_SIZE as i32,
+            hp: DEFAULT_STACK_SIZE as i32,
             r4: 0,
             r5: 0,
             r6: 0,
@@ -97,7 +99,7 @@ impl IndexMut<i32> for RegisterFile {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 pub enum Reg {
     PC,
     SP,
@@ -141,3 +143,21 @@ impl TryFrom<i32> for Reg {
         }
     }
 }
+
+impl FromStr for Reg {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "PC" | "R0" => Ok(Reg::PC),
+            "SP" | "R1" => Ok(Reg::SP),
+            "MP" | "R2" => Ok(Reg::MP),
+            "HP" | "R3" => Ok(Reg::HP),
+            "R4" => Ok(Reg::R4),
+            "RR" | "R5" => Ok(Reg::R5),
+            "R6" => Ok(Reg::R6),
+            "R7" => Ok(Reg::R7),
+            _ => Err("Invalid register"),
+        }
+    }
+}
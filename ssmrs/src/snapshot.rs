@@ -0,0 +1,139 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{ConfigError, CpuConfig},
+    register::RegisterFile,
+};
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// The complete state of a [`Cpu`](crate::Cpu), apart from its devices and its step history.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub config: CpuConfig,
+    pub registers: RegisterFile,
+    pub memory: Vec<i32>,
+    pub heap: Vec<i32>,
+    pub code_size: usize,
+    #[serde(default)]
+    pub instructions: u64,
+    pub labels: BTreeMap<String, i32>,
+    pub breakpoints: BTreeSet<i32>,
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("snapshots always serialize")
+    }
+
+    pub fn from_json(json: &str) -> Result<Snapshot, SnapshotError> {
+        let snapshot: Snapshot =
+            serde_json::from_str(json).map_err(|e| SnapshotError::Format(e.to_string()))?;
+        snapshot.validate()?;
+        Ok(snapshot)
+    }
+
+    pub fn validate(&self) -> Result<(), SnapshotError> {
+        if self.version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(self.version));
+        }
+        self.config.validate().map_err(SnapshotError::Config)?;
+        if self.memory.len() != self.config.stack_size
+            || self.code_size > self.memory.len()
+            || self.heap.len() > self.config.heap_limit - self.config.heap_start
+        {
+            return Err(SnapshotError::SizeMismatch);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SnapshotError {
+    Format(String),
+    UnsupportedVersion(u32),
+    Config(ConfigError),
+    SizeMismatch,
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Format(e) => write!(f, "invalid snapshot: {}", e),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {}", v)
+            }
+            SnapshotError::Config(e) => write!(f, "invalid snapshot: {}", e),
+            SnapshotError::SizeMismatch => {
+                write!(f, "invalid snapshot: memory does not match the layout")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+#[cfg(test)]
+mod tests {
+    use chumsky::Parser;
+
+    use super::{Snapshot, SnapshotError};
+    use crate::{debug::RunLimits, io::BufferIo, Cpu, StopReason};
+
+    #[test]
+    fn snapshot_round_trip() {
+        let src = "LDC 7\nSTH\nAJS -1\nLDC 3\nloop: LDS 0\nTRAP 0\nLDC 1\nSUB\nLDS 0\nLDC 0\nNE\nBRT loop\nHALT";
+        let io = BufferIo::new();
+        let mut cpu = Cpu::new(0, Box::new(io.clone()));
+        cpu.load_code(crate::parse().parse(src).unwrap()).unwrap();
+        cpu.add_label_breakpoint("loop");
+        assert_eq!(
+            cpu.run_until(RunLimits::default()),
+            StopReason::Breakpoint(7)
+        );
+
+        let json = cpu.snapshot().to_json();
+        let snapshot = Snapshot::from_json(&json).unwrap();
+        assert_eq!(snapshot, cpu.snapshot());
+        let resumed_io = BufferIo::new();
+        let mut resumed = Cpu::from_snapshot(snapshot, 0, Box::new(resumed_io.clone())).unwrap();
+        assert_eq!(resumed.read_registers(), cpu.read_registers());
+        assert_eq!(
+            resumed.run_until(RunLimits::default()),
+            StopReason::Breakpoint(7)
+        );
+        assert_eq!(
+            cpu.run_until(RunLimits::default()),
+            StopReason::Breakpoint(7)
+        );
+        assert_eq!(resumed.snapshot(), cpu.snapshot());
+        assert_eq!(resumed.read_heap(), &[7]);
+        assert_eq!(resumed_io.output(), "3\n");
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        let cpu = Cpu::new(0, Box::new(BufferIo::new()));
+        let mut snapshot = cpu.snapshot();
+        snapshot.memory.pop();
+        assert_eq!(
+            Snapshot::from_json(&snapshot.to_json()),
+            Err(SnapshotError::SizeMismatch)
+        );
+        snapshot.version = 0;
+        assert_eq!(
+            Snapshot::from_json(&snapshot.to_json()),
+            Err(SnapshotError::UnsupportedVersion(0))
+        );
+        assert!(matches!(
+            Snapshot::from_json("{}"),
+            Err(SnapshotError::Format(_))
+        ));
+    }
+}
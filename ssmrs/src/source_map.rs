@@ -0,0 +1,108 @@
+use std::{collections::BTreeMap, fmt::Display, ops::Range};
+
+use crate::{diagnostic::Sources, SourceInstr};
+
+/// The place in a source file an instruction was assembled from.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Location {
+    pub file: String,
+    /// Character offsets in the file.
+    pub span: Range<usize>,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+/// Maps code addresses back to the source they were assembled from.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct SourceMap {
+    locations: BTreeMap<i32, Location>,
+}
+
+impl SourceMap {
+    /// Builds the map for code with spans in `sources`, with the code loaded at address 0.
+    pub fn new(sources: &Sources, code: &[(SourceInstr, Range<usize>)]) -> Self {
+        let mut map = SourceMap::default();
+        let mut addr = 0;
+        for (instr, span) in code {
+            let size = instr.instr_size();
+            if let (true, Some((file, line, col))) = (size > 0, sources.locate(span.start)) {
+                map.insert(
+                    addr as i32,
+                    Location {
+                        file: file.name.clone(),
+                        span: span.start - file.start..span.end - file.start,
+                        line,
+                        col,
+                    },
+                );
+            }
+            addr += size;
+        }
+        map
+    }
+
+    pub fn insert(&mut self, addr: i32, location: Location) {
+        self.locations.insert(addr, location);
+    }
+
+    /// The location of the instruction starting at `addr`.
+    pub fn get(&self, addr: i32) -> Option<&Location> {
+        self.locations.get(&addr)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (i32, &Location)> {
+        self.locations
+            .iter()
+            .map(|(&addr, location)| (addr, location))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assemble, io::BufferIo, Cpu, CpuFault};
+
+    #[test]
+    fn maps_addresses_to_lines() {
+        let src = "main:\n  LDC 0\n  ANNOTE SP 0 0 red x\n  BRT main\n\n  LDC 1\n  LDC 0\n  DIV";
+        let assembly = assemble("div.ssm", src).unwrap();
+        let lines = assembly
+            .source_map
+            .iter()
+            .map(|(addr, location)| (addr, location.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                (0, "div.ssm:2:3".to_string()),
+                (2, "div.ssm:4:3".to_string()),
+                (4, "div.ssm:6:3".to_string()),
+                (6, "div.ssm:7:3".to_string()),
+                (8, "div.ssm:8:3".to_string()),
+            ]
+        );
+
+        let io = BufferIo::new();
+        let mut cpu = Cpu::new(1, Box::new(io.clone()));
+        cpu.load_assembly(assembly).unwrap();
+        cpu.step().unwrap();
+        assert!(io
+            .logs()
+            .contains(&"Executing LDC(0) at div.ssm:2:3".to_string()));
+        let fault: CpuFault = loop {
+            if let Err(fault) = cpu.step() {
+                break fault;
+            }
+        };
+        assert_eq!(cpu.source_map().get(fault.pc).unwrap().line, 8);
+    }
+}